@@ -0,0 +1,298 @@
+// Analysis of the audio that goes into a patch.
+
+use crate::keys;
+
+const YIN_WINDOW: usize = 2048;
+const YIN_MAX_LAG: usize = 2048; // ~21 Hz at 44.1 kHz
+const YIN_THRESHOLD: f32 = 0.1;
+const NUM_WINDOWS: usize = 9;
+
+/// Estimate the fundamental frequency of a pitched sample, using YIN on several windows of its
+/// stable portion. The first fifth of the sample is skipped so that the attack is ignored.
+/// Returns `None` if no pitch can be found.
+pub fn detect_pitch(data: &[i16], sample_rate: u32) -> Option<f32> {
+    let data: Vec<f32> = data.iter().map(|&x| x as f32 / 32768.0).collect();
+    let stable = &data[data.len() / 5..];
+    let needed = YIN_WINDOW + YIN_MAX_LAG;
+    if stable.len() < needed {
+        return None;
+    }
+
+    let step = (stable.len() - needed) / NUM_WINDOWS;
+    let mut estimates: Vec<f32> = (0..NUM_WINDOWS)
+        .filter_map(|i| yin(&stable[i * step..i * step + needed]))
+        .map(|period| sample_rate as f32 / period)
+        .collect();
+    if estimates.len() < NUM_WINDOWS / 2 {
+        return None;
+    }
+    estimates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    Some(estimates[estimates.len() / 2])
+}
+
+/// The period of `window`, in samples.
+fn yin(window: &[f32]) -> Option<f32> {
+    let mut diff = vec![0.0; YIN_MAX_LAG];
+    for (tau, d) in diff.iter_mut().enumerate().skip(1) {
+        *d = (0..YIN_WINDOW)
+            .map(|j| window[j] - window[j + tau])
+            .map(|x| x * x)
+            .sum();
+    }
+
+    // Cumulative mean normalized difference
+    let mut sum = 0.0;
+    let mut cmnd = vec![1.0; YIN_MAX_LAG];
+    for tau in 1..YIN_MAX_LAG {
+        sum += diff[tau];
+        cmnd[tau] = if sum > 0.0 {
+            diff[tau] * tau as f32 / sum
+        } else {
+            1.0
+        };
+    }
+
+    let mut tau = (2..YIN_MAX_LAG).find(|&tau| cmnd[tau] < YIN_THRESHOLD)?;
+    while tau + 1 < YIN_MAX_LAG && cmnd[tau + 1] < cmnd[tau] {
+        tau += 1;
+    }
+
+    // Parabolic interpolation around the minimum
+    if tau + 1 < YIN_MAX_LAG {
+        let (a, b, c) = (cmnd[tau - 1], cmnd[tau], cmnd[tau + 1]);
+        let denom = a - 2.0 * b + c;
+        if denom.abs() > f32::EPSILON {
+            return Some(tau as f32 + (a - c) / (2.0 * denom));
+        }
+    }
+    Some(tau as f32)
+}
+
+/// The frequency of a MIDI note, where 69 is A4 at 440 Hz.
+pub fn midi_note_to_freq(note: f32) -> f32 {
+    440.0 * 2f32.powf((note - 69.0) / 12.0)
+}
+
+/// The MIDI note of a frequency, where 440 Hz is A4, 69.
+pub fn freq_to_midi_note(freq: f32) -> f32 {
+    69.0 + 12.0 * (freq / 440.0).log2()
+}
+
+/// Find a note name in a filename, such as `pad_C3.wav` or `bass-F#1.wav`, and return the MIDI
+/// note number. Notes are named as in `keys::parse_note`. The last note name in the filename
+/// wins.
+pub fn note_from_filename(path: &str) -> Option<u8> {
+    let stem = std::path::Path::new(path).file_stem()?.to_str()?;
+    stem.split(['_', '-', ' ', '.'])
+        .rev()
+        .find_map(keys::parse_note)
+}
+
+const LOOP_WINDOW: usize = 1024;
+const MIN_LOOP_SECONDS: f32 = 0.1;
+const MAX_LOOP_CANDIDATES: usize = 2000;
+const MAX_CROSSFADE: usize = 2048;
+
+/// Find a sustain loop in a sample, returning its start and (exclusive) end in samples.
+///
+/// Both ends of the loop are rising zero crossings. The end is the last one before the final
+/// twentieth of the sample, and the start is the one in the stable portion whose preceding
+/// audio best matches the audio preceding the end, so that the jump is as smooth as possible.
+pub fn find_loop(data: &[i16], sample_rate: u32) -> Option<(usize, usize)> {
+    let data: Vec<f32> = data.iter().map(|&x| x as f32 / 32768.0).collect();
+    let crossings: Vec<usize> = (1..data.len())
+        .filter(|&i| data[i - 1] < 0.0 && data[i] >= 0.0)
+        .collect();
+    let min_loop = (sample_rate as f32 * MIN_LOOP_SECONDS) as usize;
+
+    let end = *crossings
+        .iter()
+        .rev()
+        .find(|&&i| i <= data.len() - data.len() / 20)?;
+    let earliest = LOOP_WINDOW.max(data.len() / 5);
+    if end < earliest + min_loop {
+        return None;
+    }
+    let candidates: Vec<usize> = crossings
+        .iter()
+        .copied()
+        .filter(|&i| i >= earliest && i + min_loop <= end)
+        .collect();
+    let step = (candidates.len() / MAX_LOOP_CANDIDATES).max(1);
+
+    let target = &data[end - LOOP_WINDOW..end];
+    let target_energy: f32 = target.iter().map(|x| x * x).sum();
+    let mismatch = |start: usize| {
+        let window = &data[start - LOOP_WINDOW..start];
+        let diff: f32 = window
+            .iter()
+            .zip(target)
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum();
+        let energy: f32 = window.iter().map(|x| x * x).sum();
+        diff / (energy + target_energy + f32::EPSILON)
+    };
+
+    let start = candidates
+        .into_iter()
+        .step_by(step)
+        .map(|start| (start, mismatch(start)))
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())?
+        .0;
+    Some((start, end))
+}
+
+/// Bake an equal-power crossfade into the end of a loop, so that the audio leading up to `end`
+/// fades into the audio leading up to `start`, and jumping from `end` to `start` is seamless.
+pub fn crossfade_loop(data: &mut [i16], start: usize, end: usize) {
+    let len = MAX_CROSSFADE.min(start).min((end - start) / 2);
+    for i in 0..len {
+        let t = (i + 1) as f32 / len as f32 * std::f32::consts::FRAC_PI_2;
+        let out = data[end - len + i] as f32 * t.cos() + data[start - len + i] as f32 * t.sin();
+        data[end - len + i] = out.round().max(i16::MIN as f32).min(i16::MAX as f32) as i16;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const RATE: u32 = 44100;
+
+    /// One second of a waveform, given as a function of the phase in cycles.
+    fn tone(freq: f32, wave: impl Fn(f32) -> f32) -> Vec<i16> {
+        (0..RATE)
+            .map(|i| (wave((i as f32 * freq / RATE as f32).fract()) * 16000.0) as i16)
+            .collect()
+    }
+
+    fn sine(phase: f32) -> f32 {
+        (phase * 2.0 * PI).sin()
+    }
+
+    fn saw(phase: f32) -> f32 {
+        if phase < 0.5 {
+            phase * 2.0
+        } else {
+            phase * 2.0 - 2.0
+        }
+    }
+
+    fn cents(a: f32, b: f32) -> f32 {
+        (1200.0 * (a / b).log2()).abs()
+    }
+
+    #[test]
+    fn pitch_of_a_sine() {
+        for &freq in &[55.0, 261.63, 440.0, 1760.0] {
+            let detected = detect_pitch(&tone(freq, sine), RATE).unwrap();
+            assert!(
+                cents(detected, freq) < 5.0,
+                "{} detected as {}",
+                freq,
+                detected
+            );
+        }
+    }
+
+    #[test]
+    fn pitch_of_a_saw() {
+        for &freq in &[110.0, 329.63] {
+            let detected = detect_pitch(&tone(freq, saw), RATE).unwrap();
+            assert!(
+                cents(detected, freq) < 5.0,
+                "{} detected as {}",
+                freq,
+                detected
+            );
+        }
+    }
+
+    #[test]
+    fn no_pitch_in_silence_or_short_samples() {
+        assert_eq!(detect_pitch(&[0; RATE as usize], RATE), None);
+        assert_eq!(detect_pitch(&tone(440.0, sine)[..4096], RATE), None);
+    }
+
+    #[test]
+    fn note_frequencies() {
+        assert_eq!(midi_note_to_freq(69.0), 440.0);
+        assert!((midi_note_to_freq(60.0) - 261.63).abs() < 0.01);
+        assert!((freq_to_midi_note(midi_note_to_freq(42.0)) - 42.0).abs() < 0.001);
+    }
+
+    fn is_rising_crossing(data: &[i16], i: usize) -> bool {
+        data[i - 1] < 0 && data[i] >= 0
+    }
+
+    #[test]
+    fn loop_points_are_rising_zero_crossings() {
+        for data in &[tone(220.0, sine), tone(110.0, saw)] {
+            let (start, end) = find_loop(data, RATE).unwrap();
+            assert!(is_rising_crossing(data, start), "start {}", start);
+            assert!(is_rising_crossing(data, end), "end {}", end);
+            assert!(start >= data.len() / 5);
+            assert!(end - start >= RATE as usize / 10);
+            assert!(end <= data.len() - data.len() / 20);
+        }
+    }
+
+    #[test]
+    fn loop_of_a_sine_spans_whole_periods() {
+        let data = tone(200.0, sine);
+        let (start, end) = find_loop(&data, RATE).unwrap();
+        // 200 Hz has a period of 220.5 samples, so whole periods are within a sample of a
+        // multiple of it
+        let periods = (end - start) as f32 / 220.5;
+        assert!(
+            (periods - periods.round()).abs() * 220.5 <= 1.0,
+            "{}",
+            periods
+        );
+    }
+
+    #[test]
+    fn no_loop_in_short_samples() {
+        assert_eq!(find_loop(&tone(220.0, sine)[..4410], RATE), None);
+        assert_eq!(find_loop(&[0; RATE as usize], RATE), None);
+    }
+
+    #[test]
+    fn crossfade_keeps_equal_power() {
+        let (start, end) = (10000, 20000);
+        // Fade a constant signal out into silence, and silence into a constant signal
+        let mut out = vec![0; 30000];
+        out[start..].iter_mut().for_each(|x| *x = 10000);
+        let mut into = vec![10000; 30000];
+        into[start..].iter_mut().for_each(|x| *x = 0);
+        let (out_before, into_before) = (out.clone(), into.clone());
+        crossfade_loop(&mut out, start, end);
+        crossfade_loop(&mut into, start, end);
+
+        let fade = end - MAX_CROSSFADE..end;
+        for i in fade.clone() {
+            let amplitude = ((out[i] as f32).powi(2) + (into[i] as f32).powi(2)).sqrt();
+            assert!((amplitude - 10000.0).abs() <= 1.0, "{} at {}", amplitude, i);
+        }
+        // The fade ends on the audio before the start, so the jump is seamless
+        assert_eq!(out[end - 1], out[start - 1]);
+        assert_eq!(into[end - 1], into[start - 1]);
+        // Nothing outside the fade changes
+        for i in (0..out.len()).filter(|i| !fade.contains(i)) {
+            assert_eq!(out[i], out_before[i]);
+            assert_eq!(into[i], into_before[i]);
+        }
+    }
+
+    #[test]
+    fn crossfade_fits_in_the_loop() {
+        // The fade is limited to half the loop, and to the audio before the start
+        let mut data = vec![1000; 1000];
+        crossfade_loop(&mut data, 100, 900);
+        assert!(data[..800].iter().all(|&x| x == 1000));
+        let mut data = vec![1000; 1000];
+        crossfade_loop(&mut data, 500, 700);
+        assert!(data[..600].iter().all(|&x| x == 1000));
+    }
+}
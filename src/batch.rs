@@ -0,0 +1,263 @@
+// Building patches from a tree of directories: one patch for every directory of samples, or
+// for every kit manifest.
+
+use crate::chunks::{Chunk, FormChunk};
+use crate::manifest::Manifest;
+use crate::op1::Device;
+use crate::patch;
+use std::error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// The lowest and highest notes of the keyboard, used to pick the source of a synth patch.
+const KEYBOARD_RANGE: (f32, f32) = (53.0, 76.0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PatchType {
+    Drum,
+    Synth,
+}
+
+#[derive(Debug)]
+pub enum Source {
+    /// A directory of samples.
+    Samples(PathBuf),
+    Manifest(PathBuf),
+}
+
+#[derive(Debug)]
+pub struct Job {
+    pub source: Source,
+    pub output: PathBuf,
+}
+
+/// The length of a built patch's audio, and the most that it could hold.
+pub struct Usage {
+    pub seconds: f32,
+    pub max_seconds: usize,
+}
+
+pub struct Outcome {
+    pub job: Job,
+    pub result: Result<Usage, String>,
+}
+
+fn is_sample(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ["wav", "aif", "aiff"].contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+fn is_manifest(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some("toml")
+}
+
+/// Find the patches to build in the tree under `input`. A directory containing manifests
+/// produces one patch per manifest; otherwise a directory containing samples produces one
+/// patch. Outputs are placed at the same relative paths under `output`.
+pub fn find_jobs(input: &Path, output: &Path) -> Result<Vec<Job>, io::Error> {
+    let mut jobs = vec![];
+    let skip = output.canonicalize().ok();
+    let root_name = input
+        .canonicalize()?
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_else(|| "patch".into());
+    let mut dirs = vec![input.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries: Vec<PathBuf> = fs::read_dir(&dir)?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| {
+                path.file_name()
+                    .is_some_and(|n| !n.to_string_lossy().starts_with('.'))
+            })
+            .collect();
+        entries.sort();
+
+        let relative = dir.strip_prefix(input).unwrap_or(&dir);
+        let manifests: Vec<&PathBuf> = entries.iter().filter(|p| is_manifest(p)).collect();
+        if !manifests.is_empty() {
+            for manifest in manifests {
+                let name = manifest.file_stem().unwrap();
+                jobs.push(Job {
+                    source: Source::Manifest(manifest.clone()),
+                    output: aif_path(output.join(relative).join(name)),
+                });
+            }
+        } else if entries.iter().any(|p| is_sample(p)) {
+            let out = if relative.as_os_str().is_empty() {
+                output.join(&root_name)
+            } else {
+                output.join(relative)
+            };
+            jobs.push(Job {
+                source: Source::Samples(dir.clone()),
+                output: aif_path(out),
+            });
+        }
+
+        for entry in entries.into_iter().rev() {
+            if entry.is_dir() && entry.canonicalize().ok() != skip {
+                dirs.push(entry);
+            }
+        }
+    }
+    jobs.sort_by(|a, b| a.output.cmp(&b.output));
+    Ok(jobs)
+}
+
+/// `path` with `.aif` appended. Unlike `with_extension`, this keeps any dots in the name.
+fn aif_path(path: PathBuf) -> PathBuf {
+    let mut path = path.into_os_string();
+    path.push(".aif");
+    path.into()
+}
+
+/// A patch name from the name of its output: printable ASCII only, and short enough for the
+/// OP-Z.
+fn patch_name(path: &Path) -> String {
+    let name: String = path
+        .file_stem()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_graphic() || *c == ' ')
+        .take(16)
+        .collect();
+    if name.trim().is_empty() {
+        "user".to_string()
+    } else {
+        name
+    }
+}
+
+fn build(
+    job: &Job,
+    patch_type: PatchType,
+    device: Device,
+) -> Result<(FormChunk, usize), Box<dyn error::Error>> {
+    let name = patch_name(&job.output);
+    Ok(match &job.source {
+        Source::Manifest(path) => {
+            let dir = path.parent().unwrap_or_else(|| Path::new("."));
+            let form = Manifest::read(path)?.build(dir, device, &name)?;
+            (form, device.max_drum_seconds())
+        }
+        Source::Samples(dir) if patch_type == PatchType::Drum => {
+            let mut files: Vec<String> = fs::read_dir(dir)?
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| is_sample(path))
+                .map(|path| path.to_string_lossy().to_string())
+                .collect();
+            files.sort();
+            if files.len() > 24 {
+                Err(format!(
+                    "{} samples found, but a drum patch holds 24",
+                    files.len()
+                ))?;
+            }
+            let files: Vec<&str> = files.iter().map(|f| f.as_str()).collect();
+            let options = patch::DrumOptions::default();
+            let form = patch::drum_form(&files, &options, device, &name)?;
+            (form, device.max_drum_seconds())
+        }
+        Source::Samples(dir) => {
+            let sources = patch::read_multisample(&dir.to_string_lossy())?;
+            let source = patch::best_multisample(sources, KEYBOARD_RANGE);
+            let note = source.root_note.unwrap();
+            let base_freq = crate::audio::midi_note_to_freq(note).round() as u16;
+            let form = patch::synth_form(
+                source.samples,
+                source.sample_loop,
+                base_freq,
+                false,
+                device,
+                &name,
+            )?;
+            (form, device.max_synth_seconds())
+        }
+    })
+}
+
+fn run_job(job: &Job, patch_type: PatchType, device: Device) -> Result<Usage, String> {
+    let (form, max_seconds) = build(job, patch_type, device).map_err(|e| e.to_string())?;
+    if let Some(parent) = job.output.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    crate::write_file(&job.output, false, |file| form.write(file)).map_err(|e| e.to_string())?;
+
+    let bytes = form.sound.as_ref().map_or(0, |s| s.sound_data.len());
+    Ok(Usage {
+        seconds: bytes as f32 / 2.0 / 44100.0,
+        max_seconds,
+    })
+}
+
+/// Build every job, using up to `threads` threads.
+pub fn run(jobs: Vec<Job>, patch_type: PatchType, device: Device, threads: usize) -> Vec<Outcome> {
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<Usage, String>>>> =
+        Mutex::new(jobs.iter().map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1).min(jobs.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let job = match jobs.get(i) {
+                    Some(job) => job,
+                    None => break,
+                };
+                log::info!("Building {}", job.output.display());
+                let result = run_job(job, patch_type, device);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+
+    jobs.into_iter()
+        .zip(results.into_inner().unwrap())
+        .map(|(job, result)| Outcome {
+            job,
+            result: result.unwrap(),
+        })
+        .collect()
+}
+
+/// Print a table of the outcome of every job, with how much of its length budget each patch
+/// uses.
+pub fn print_summary(outcomes: &[Outcome]) {
+    let width = outcomes
+        .iter()
+        .map(|o| o.job.output.display().to_string().len())
+        .chain(std::iter::once(6))
+        .max()
+        .unwrap();
+    println!(
+        "{:<6}  {:<width$}  {:>7}  {:>6}",
+        "STATUS",
+        "OUTPUT",
+        "LENGTH",
+        "BUDGET",
+        width = width
+    );
+    for outcome in outcomes.iter() {
+        let output = outcome.job.output.display();
+        match &outcome.result {
+            Ok(usage) => println!(
+                "{:<6}  {:<width$}  {:>6.2}s  {:>5.0}%",
+                "ok",
+                output,
+                usage.seconds,
+                usage.seconds / usage.max_seconds as f32 * 100.0,
+                width = width
+            ),
+            Err(e) => println!("{:<6}  {:<width$}  {}", "FAILED", output, e, width = width),
+        }
+    }
+    let failed = outcomes.iter().filter(|o| o.result.is_err()).count();
+    println!("\n{} built, {} failed", outcomes.len() - failed, failed);
+}
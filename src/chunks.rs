@@ -3,7 +3,6 @@
 use crate::op1::OP1Data;
 use crate::util::*;
 
-use log;
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 use std::{error, fmt};
 
@@ -33,40 +32,140 @@ pub type Buffer<'a> = &'a mut Cursor<Vec<u8>>;
 
 pub fn read_aif(file: &mut impl Read) -> Result<FormChunk, ChunkError> {
     let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).unwrap();
+    file.read_to_end(&mut buffer)?;
     let mut cursor = Cursor::new(buffer);
     FormChunk::parse(&mut cursor)
 }
 
+/// The header of a chunk within a FORM, read without parsing the chunk.
+#[derive(Debug)]
+pub struct ChunkHeader {
+    pub id: ChunkID,
+    /// The offset of the chunk's ID from the start of the file.
+    pub offset: usize,
+    /// The size the chunk declares, not counting its ID, size or pad byte.
+    pub size: i32,
+    /// How many bytes of the chunk's data are actually present.
+    pub available: usize,
+}
+
+/// The headers of the chunks within the FORM in `bytes`, in order. This follows the declared
+/// sizes as far as the data allows, so it works on files that are truncated or whose FORM size
+/// is wrong.
+pub fn scan_chunks(bytes: &[u8]) -> Vec<ChunkHeader> {
+    let mut chunks = vec![];
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let mut id = [0; 4];
+        id.copy_from_slice(&bytes[offset..offset + 4]);
+        let size = i32::from_be_bytes([
+            bytes[offset + 4],
+            bytes[offset + 5],
+            bytes[offset + 6],
+            bytes[offset + 7],
+        ]);
+        let remaining = bytes.len() - offset - 8;
+        let available = (size.max(0) as usize).min(remaining);
+        chunks.push(ChunkHeader {
+            id,
+            offset,
+            size,
+            available,
+        });
+        if size < 0 || available < size as usize {
+            break;
+        }
+        offset += 8 + size as usize + (size as usize & 1);
+    }
+    chunks
+}
+
+/// Parse a chunk from its complete bytes, including its ID. The pad byte that follows a chunk
+/// with an odd size may be left out.
+pub fn parse_chunk<T: Chunk>(bytes: &[u8]) -> Result<T, ChunkError> {
+    let mut bytes = bytes.to_vec();
+    if bytes.len() % 2 == 1 {
+        bytes.push(0);
+    }
+    let mut cursor = Cursor::new(bytes);
+    cursor.set_position(4);
+    T::parse(&mut cursor)
+}
+
+/// Read `size` bytes of chunk data, failing if the size is negative or the data ends first.
+fn read_data(buf: Buffer, size: i32) -> Result<Vec<u8>, ChunkError> {
+    if size < 0 {
+        return Err(ChunkError::InvalidData(format!(
+            "Negative chunk size {}",
+            size
+        )));
+    }
+    let remaining = buf.get_ref().len().saturating_sub(buf.position() as usize);
+    if size as usize > remaining {
+        return Err(ChunkError::UnexpectedEnd);
+    }
+    let mut data = vec![0; size as usize];
+    buf.read_exact(&mut data)?;
+    Ok(data)
+}
+
 #[derive(Debug)]
 pub enum ChunkError {
     InvalidID(ChunkID),
     InvalidFormType(ChunkID),
     InvalidSize(i32, i32), // expected, got,
     InvalidData(String),   // failed to parse something
+    UnexpectedEnd,         // the data ended before the chunk did
 }
 
 impl fmt::Display for ChunkError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Self::InvalidID(id) => write!(f, "Unexpected chunk {:?}", String::from_utf8_lossy(id)),
+            Self::InvalidFormType(id) => {
+                write!(f, "Unknown FORM type {:?}", String::from_utf8_lossy(id))
+            }
+            Self::InvalidSize(expected, got) => {
+                write!(f, "Chunk size is {}, expected {}", got, expected)
+            }
+            Self::InvalidData(message) => f.write_str(message),
+            Self::UnexpectedEnd => f.write_str("Chunk data ends early"),
+        }
     }
 }
 impl error::Error for ChunkError {}
 
+impl From<io::Error> for ChunkError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Self::UnexpectedEnd,
+            _ => Self::InvalidData(e.to_string()),
+        }
+    }
+}
+
 pub trait Chunk {
     fn parse(buffer: Buffer) -> Result<Self, ChunkError>
     where
         Self: Sized;
 
-    fn write(&self, _file: &mut impl Write) -> Result<usize, io::Error> {
-        // unimplemented!(); TODO
-        Ok(0)
+    fn write(&self, file: &mut impl Write) -> Result<usize, io::Error>;
+}
+
+/// Write a chunk holding `data`, followed by a pad byte if its length is odd.
+fn write_padded(file: &mut impl Write, id: &ChunkID, data: &[u8]) -> Result<usize, io::Error> {
+    file.write_all(id)?;
+    file.write_all(&(data.len() as i32).to_be_bytes())?;
+    file.write_all(data)?;
+    if !data.len().is_multiple_of(2) {
+        file.write_all(&[0])?;
+        return Ok(data.len() + 9);
     }
+    Ok(data.len() + 8)
 }
 
 #[derive(Debug)]
 pub struct FormChunk {
-    pub size: i32,
     pub form_type: ChunkID,
     pub common: CommonChunk,
     pub sound: Option<SoundDataChunk>,
@@ -82,7 +181,6 @@ pub struct FormChunk {
 impl Default for FormChunk {
     fn default() -> Self {
         Self {
-            size: 0,
             form_type: *AIFF,
             common: Default::default(),
             sound: Default::default(),
@@ -99,15 +197,14 @@ impl Default for FormChunk {
 
 impl Chunk for FormChunk {
     fn parse(buf: Buffer) -> Result<FormChunk, ChunkError> {
-        let id = read_chunk_id(buf);
+        let id = read_chunk_id(buf)?;
         if &id != FORM {
             return Err(ChunkError::InvalidID(id));
         }
 
-        let size = read_i32_be(buf);
+        let size = read_i32_be(buf)?;
         log::info!("form chunk bytes {}", size);
-        let mut form_type = [0; 4];
-        buf.read_exact(&mut form_type).unwrap();
+        let form_type = read_chunk_id(buf)?;
         match &form_type {
             AIFF | AIFF_C => (),
             _ => Err(ChunkError::InvalidFormType(form_type))?,
@@ -154,21 +251,22 @@ impl Chunk for FormChunk {
                     texts.push(TextChunk::parse(buf)?);
                 }
                 FORMAT_VER => {
-                    unimplemented!("FVER chunk detected");
+                    return Err(ChunkError::InvalidData(
+                        "AIFF-C files are not supported".to_string(),
+                    ))
                 }
                 _ => return Err(ChunkError::InvalidID(id)),
             };
         }
 
-        let mut common = common.unwrap();
+        let mut common =
+            common.ok_or_else(|| ChunkError::InvalidData("No COMM chunk".to_string()))?;
+        common.check_format().map_err(ChunkError::InvalidData)?;
         common.num_sample_frames = sound.as_ref().map_or(0, |s| {
-            s.sound_data.len() as u32
-                / (common.bit_rate / 8).max(1) as u32
-                / common.num_channels as u32
+            (s.sound_data.len() / common.bytes_per_frame()) as u32
         });
 
         Ok(FormChunk {
-            size,
             form_type,
             common,
             sound,
@@ -185,10 +283,10 @@ impl Chunk for FormChunk {
     fn write(&self, file: &mut impl Write) -> Result<usize, io::Error> {
         let mut cursor = Cursor::new(vec![]);
 
-        cursor.write(FORM)?;
+        cursor.write_all(FORM)?;
 
-        cursor.write(&0i32.to_be_bytes())?;
-        cursor.write(&self.form_type)?;
+        cursor.write_all(&0i32.to_be_bytes())?;
+        cursor.write_all(&self.form_type)?;
         let mut size = 4; // form_type
 
         size += self.common.write(&mut cursor)?;
@@ -220,10 +318,10 @@ impl Chunk for FormChunk {
         }
 
         cursor.seek(SeekFrom::Start(4))?;
-        cursor.write(&(size as i32).to_be_bytes())?;
+        cursor.write_all(&(size as i32).to_be_bytes())?;
 
         cursor.seek(SeekFrom::Start(0))?;
-        file.write(cursor.get_ref())?;
+        file.write_all(cursor.get_ref())?;
 
         Ok(size + 8)
     }
@@ -248,17 +346,47 @@ impl Default for CommonChunk {
     }
 }
 
+impl CommonChunk {
+    /// Check that the format can describe audio at all: at least one channel, and samples of 1
+    /// to 32 bits.
+    pub fn check_format(&self) -> Result<(), String> {
+        if self.num_channels < 1 {
+            return Err(format!("COMM: {} channels", self.num_channels));
+        }
+        if !(1..=32).contains(&self.bit_rate) {
+            return Err(format!("COMM: {}-bit samples", self.bit_rate));
+        }
+        Ok(())
+    }
+
+    /// The size of a sample frame in bytes, with a sample for each channel. Never 0, even if the
+    /// format is invalid.
+    pub fn bytes_per_frame(&self) -> usize {
+        self.num_channels.max(1) as usize * (self.bit_rate.max(1) as usize).div_ceil(8)
+    }
+
+    /// Decode the 80 bit extended `sample_rate`.
+    pub fn sample_rate_hz(&self) -> f64 {
+        let exponent = (u16::from_be_bytes([self.sample_rate[0], self.sample_rate[1]]) & 0x7fff)
+            as i32
+            - 16383;
+        let mut mantissa = [0; 8];
+        mantissa.copy_from_slice(&self.sample_rate[2..]);
+        u64::from_be_bytes(mantissa) as f64 * 2f64.powi(exponent - 63)
+    }
+}
+
 impl Chunk for CommonChunk {
     fn parse(buf: Buffer) -> Result<CommonChunk, ChunkError> {
         let (_size, num_channels, num_sample_frames, bit_rate) = (
-            read_i32_be(buf),
-            read_i16_be(buf),
-            read_u32_be(buf),
-            read_i16_be(buf),
+            read_i32_be(buf)?,
+            read_i16_be(buf)?,
+            read_u32_be(buf)?,
+            read_i16_be(buf)?,
         );
 
         let mut rate_buf = [0; 10]; // 1 bit sign, 15 bits exponent
-        buf.read_exact(&mut rate_buf).unwrap();
+        buf.read_exact(&mut rate_buf)?;
 
         Ok(CommonChunk {
             num_channels,
@@ -269,13 +397,13 @@ impl Chunk for CommonChunk {
     }
 
     fn write(&self, file: &mut impl Write) -> Result<usize, io::Error> {
-        file.write(COMMON)?;
-        file.write(&18i32.to_be_bytes())?;
-        file.write(&self.num_channels.to_be_bytes())?;
-        file.write(&self.num_sample_frames.to_be_bytes())?;
-        file.write(&self.bit_rate.to_be_bytes())?;
-        file.write(&self.sample_rate)?;
-        Ok(16 + 8)
+        file.write_all(COMMON)?;
+        file.write_all(&18i32.to_be_bytes())?;
+        file.write_all(&self.num_channels.to_be_bytes())?;
+        file.write_all(&self.num_sample_frames.to_be_bytes())?;
+        file.write_all(&self.bit_rate.to_be_bytes())?;
+        file.write_all(&self.sample_rate)?;
+        Ok(18 + 8)
     }
 }
 
@@ -298,13 +426,19 @@ impl fmt::Debug for SoundDataChunk {
 
 impl Chunk for SoundDataChunk {
     fn parse(buf: Buffer) -> Result<SoundDataChunk, ChunkError> {
-        let size = read_i32_be(buf);
-        let offset = read_u32_be(buf);
-        let block_size = read_u32_be(buf);
+        let size = read_i32_be(buf)?;
+        if size < 8 {
+            return Err(ChunkError::InvalidSize(8, size));
+        }
+        let offset = read_u32_be(buf)?;
+        let block_size = read_u32_be(buf)?;
 
-        let mut sound_data = vec![0u8; size as usize - 8];
+        // Only allocate as much as is present, whatever the chunk declares
+        let remaining = buf.get_ref().len().saturating_sub(buf.position() as usize);
+        let mut sound_data = vec![0u8; (size as usize - 8).min(remaining)];
+        buf.read_exact(&mut sound_data)?;
 
-        let got_size = buf.read(&mut sound_data).unwrap() as i32;
+        let got_size = sound_data.len() as i32;
         if size - 8 != got_size {
             log::warn!(
                 "Expected sound chunk of size {}, got {}",
@@ -317,67 +451,97 @@ impl Chunk for SoundDataChunk {
             size: got_size + 8,
             offset,
             block_size,
-            sound_data: sound_data[..got_size as usize].to_vec(),
+            sound_data,
         })
     }
 
     fn write(&self, file: &mut impl Write) -> Result<usize, io::Error> {
-        file.write(SOUND)?;
-        file.write(&self.size.to_be_bytes())?;
-        file.write(&self.offset.to_be_bytes())?;
-        file.write(&self.block_size.to_be_bytes())?;
-        file.write(&self.sound_data)?;
+        file.write_all(SOUND)?;
+        file.write_all(&self.size.to_be_bytes())?;
+        file.write_all(&self.offset.to_be_bytes())?;
+        file.write_all(&self.block_size.to_be_bytes())?;
+        file.write_all(&self.sound_data)?;
         Ok(self.size as usize + 8)
     }
 }
 
-type MarkerId = i16;
+pub type MarkerId = i16;
 #[derive(Debug)]
 pub struct Marker {
-    id: MarkerId,
-    position: u32,
-    marker_name: String,
+    pub id: MarkerId,
+    pub position: u32,
+    pub marker_name: String,
 }
 
 impl Marker {
-    // TODO return result
-    pub fn from_reader<R: Read>(r: &mut R) -> Marker {
-        let id = read_i16_be(r);
-        let position = read_u32_be(r);
-        let marker_name = read_pstring(r);
+    pub fn from_reader<R: Read>(r: &mut R) -> Result<Marker, ChunkError> {
+        let id = read_i16_be(r)?;
+        let position = read_u32_be(r)?;
+        let marker_name = read_pstring(r)?;
 
-        Marker {
+        Ok(Marker {
             id,
             position,
             marker_name,
-        }
+        })
+    }
+
+    fn write(&self, file: &mut impl Write) -> Result<usize, io::Error> {
+        file.write_all(&self.id.to_be_bytes())?;
+        file.write_all(&self.position.to_be_bytes())?;
+        Ok(6 + write_pstring(file, &self.marker_name)?)
     }
 }
 
 #[derive(Debug)]
 pub struct MarkerChunk {
-    pub size: i32,
     pub num_markers: u16,
     pub markers: Vec<Marker>,
 }
 
 impl Chunk for MarkerChunk {
     fn parse(buf: Buffer) -> Result<MarkerChunk, ChunkError> {
-        let size = read_i32_be(buf);
-        let num_markers = read_u16_be(buf);
+        let _size = read_i32_be(buf)?;
+        let num_markers = read_u16_be(buf)?;
         let mut markers = Vec::with_capacity(num_markers as usize);
         // is it worth it to read all markers at once ant create from buf?
         // or does the usage of BufReader make it irrelevant?
         for _ in 0..num_markers {
-            markers.push(Marker::from_reader(buf));
+            markers.push(Marker::from_reader(buf)?);
         }
 
         Ok(MarkerChunk {
-            size,
             num_markers,
             markers,
         })
     }
+
+    fn write(&self, file: &mut impl Write) -> Result<usize, io::Error> {
+        let mut cursor = Cursor::new(vec![]);
+        cursor.write_all(&(self.markers.len() as u16).to_be_bytes())?;
+        for marker in self.markers.iter() {
+            marker.write(&mut cursor)?;
+        }
+        let data = cursor.into_inner();
+
+        file.write_all(MARKER)?;
+        file.write_all(&(data.len() as i32).to_be_bytes())?;
+        file.write_all(&data)?;
+        Ok(data.len() + 8)
+    }
+}
+
+impl MarkerChunk {
+    pub fn new(markers: Vec<Marker>) -> Self {
+        Self {
+            num_markers: markers.len() as u16,
+            markers,
+        }
+    }
+
+    pub fn position(&self, id: MarkerId) -> Option<u32> {
+        self.markers.iter().find(|m| m.id == id).map(|m| m.position)
+    }
 }
 
 #[derive(Debug)]
@@ -391,14 +555,13 @@ pub enum TextChunkType {
 #[derive(Debug)]
 pub struct TextChunk {
     pub chunk_type: TextChunkType,
-    pub size: i32,
     pub text: String,
 }
 
 impl Chunk for TextChunk {
     fn parse(buf: Buffer) -> Result<TextChunk, ChunkError> {
-        buf.seek(SeekFrom::Current(-4)).unwrap();
-        let id = read_chunk_id(buf);
+        buf.seek(SeekFrom::Current(-4))?;
+        let id = read_chunk_id(buf)?;
         let chunk_type = match &id {
             NAME => TextChunkType::Name,
             AUTHOR => TextChunkType::Author,
@@ -407,79 +570,129 @@ impl Chunk for TextChunk {
             _ => return Err(ChunkError::InvalidID(id)),
         };
 
-        let size = read_i32_be(buf);
-        let mut text_bytes = vec![0; size as usize];
-        buf.read_exact(&mut text_bytes).unwrap();
-        let text = String::from_utf8(text_bytes).unwrap();
+        let size = read_i32_be(buf)?;
+        let text = String::from_utf8(read_data(buf, size)?)
+            .map_err(|e| ChunkError::InvalidData(e.to_string()))?;
 
         if size % 2 > 0 {
             // if odd, pad byte present - skip it
-            let mut skip = [0; 1];
-            buf.read_exact(&mut skip).unwrap()
+            read_u8(buf)?;
         }
 
-        Ok(TextChunk {
-            chunk_type,
-            size,
-            text,
-        })
+        Ok(TextChunk { chunk_type, text })
+    }
+
+    fn write(&self, file: &mut impl Write) -> Result<usize, io::Error> {
+        let id = match self.chunk_type {
+            TextChunkType::Name => NAME,
+            TextChunkType::Author => AUTHOR,
+            TextChunkType::Copyright => COPYRIGHT,
+            TextChunkType::Annotation => ANNOTATION,
+        };
+        write_padded(file, id, self.text.as_bytes())
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Loop {
     // 0 no looping / 1 foward loop / 2 forward backward loop - use enum?
-    play_mode: i16,
-    begin_loop: MarkerId,
-    end_loop: MarkerId,
+    pub play_mode: i16,
+    pub begin_loop: MarkerId,
+    pub end_loop: MarkerId,
 }
 
 impl Loop {
-    // TODO return result
-    pub fn from_reader(r: &mut impl Read) -> Loop {
-        let play_mode = read_i16_be(r);
-        let begin_loop = read_i16_be(r);
-        let end_loop = read_i16_be(r);
+    pub fn from_reader(r: &mut impl Read) -> Result<Loop, ChunkError> {
+        let play_mode = read_i16_be(r)?;
+        let begin_loop = read_i16_be(r)?;
+        let end_loop = read_i16_be(r)?;
 
-        Loop {
+        Ok(Loop {
             play_mode,
             begin_loop,
             end_loop,
+        })
+    }
+
+    fn write(&self, file: &mut impl Write) -> Result<usize, io::Error> {
+        file.write_all(&self.play_mode.to_be_bytes())?;
+        file.write_all(&self.begin_loop.to_be_bytes())?;
+        file.write_all(&self.end_loop.to_be_bytes())?;
+        Ok(6)
+    }
+
+    /// The start and end positions of the loop, if it plays.
+    pub fn positions(&self, markers: Option<&MarkerChunk>) -> Option<(u32, u32)> {
+        if self.play_mode == 0 {
+            return None;
         }
+        let markers = markers?;
+        Some((
+            markers.position(self.begin_loop)?,
+            markers.position(self.end_loop)?,
+        ))
     }
 }
 
 // midi note value range = 0..127 (? not the full range?)
 #[derive(Debug)]
 pub struct InstrumentChunk {
-    size: i32,
-    base_note: i8,     // MIDI
-    detune: i8,        // -50..50
-    low_note: i8,      // MIDI
-    high_note: i8,     // MIDI
-    low_velocity: i8,  // MIDI
-    high_velocity: i8, // MIDI
-    gain: i16,         // in db
-    sustain_loop: Loop,
-    release_loop: Loop,
+    pub base_note: i8,     // MIDI
+    pub detune: i8,        // -50..50
+    pub low_note: i8,      // MIDI
+    pub high_note: i8,     // MIDI
+    pub low_velocity: i8,  // MIDI
+    pub high_velocity: i8, // MIDI
+    pub gain: i16,         // in db
+    pub sustain_loop: Loop,
+    pub release_loop: Loop,
+}
+
+impl Default for InstrumentChunk {
+    fn default() -> Self {
+        Self {
+            base_note: 60,
+            detune: 0,
+            low_note: 0,
+            high_note: 127,
+            low_velocity: 1,
+            high_velocity: 127,
+            gain: 0,
+            sustain_loop: Default::default(),
+            release_loop: Default::default(),
+        }
+    }
+}
+
+impl InstrumentChunk {
+    /// The pitch of the sample as a fractional MIDI note. A positive detune means that the
+    /// sample is pitched above `base_note`.
+    pub fn root_note(&self) -> f32 {
+        self.base_note as f32 + self.detune as f32 / 100.0
+    }
+
+    pub fn set_root_note(&mut self, note: f32) {
+        let base_note = note.round().clamp(0.0, 127.0);
+        self.base_note = base_note as i8;
+        self.detune = ((note - base_note) * 100.0).round().clamp(-50.0, 50.0) as i8;
+    }
 }
 
 impl Chunk for InstrumentChunk {
     fn parse(buf: Buffer) -> Result<InstrumentChunk, ChunkError> {
-        let size = read_i32_be(buf);
-        let base_note = read_i8_be(buf);
-        let detune = read_i8_be(buf);
-        let low_note = read_i8_be(buf);
-        let high_note = read_i8_be(buf);
-        let low_velocity = read_i8_be(buf);
-        let high_velocity = read_i8_be(buf);
-        let gain = read_i16_be(buf);
-
-        let sustain_loop = Loop::from_reader(buf);
-        let release_loop = Loop::from_reader(buf);
+        let _size = read_i32_be(buf)?;
+        let base_note = read_i8_be(buf)?;
+        let detune = read_i8_be(buf)?;
+        let low_note = read_i8_be(buf)?;
+        let high_note = read_i8_be(buf)?;
+        let low_velocity = read_i8_be(buf)?;
+        let high_velocity = read_i8_be(buf)?;
+        let gain = read_i16_be(buf)?;
+
+        let sustain_loop = Loop::from_reader(buf)?;
+        let release_loop = Loop::from_reader(buf)?;
 
         Ok(InstrumentChunk {
-            size,
             base_note,
             detune,
             low_note,
@@ -491,28 +704,50 @@ impl Chunk for InstrumentChunk {
             release_loop,
         })
     }
+
+    fn write(&self, file: &mut impl Write) -> Result<usize, io::Error> {
+        file.write_all(INSTRUMENT)?;
+        file.write_all(&20i32.to_be_bytes())?;
+        file.write_all(&[
+            self.base_note as u8,
+            self.detune as u8,
+            self.low_note as u8,
+            self.high_note as u8,
+            self.low_velocity as u8,
+            self.high_velocity as u8,
+        ])?;
+        file.write_all(&self.gain.to_be_bytes())?;
+        self.sustain_loop.write(file)?;
+        self.release_loop.write(file)?;
+        Ok(20 + 8)
+    }
 }
 
 #[derive(Debug)]
 pub struct MIDIDataChunk {
-    size: i32,
     data: Vec<u8>,
 }
 
 impl Chunk for MIDIDataChunk {
     fn parse(buf: Buffer) -> Result<MIDIDataChunk, ChunkError> {
-        let size = read_i32_be(buf);
+        let size = read_i32_be(buf)?;
+        let data = read_data(buf, size)?;
 
-        let mut data = vec![0; size as usize];
-        buf.read_exact(&mut data).unwrap();
+        if size % 2 > 0 {
+            // if odd, pad byte present - skip it
+            read_u8(buf)?;
+        }
 
-        Ok(MIDIDataChunk { size, data })
+        Ok(MIDIDataChunk { data })
+    }
+
+    fn write(&self, file: &mut impl Write) -> Result<usize, io::Error> {
+        write_padded(file, MIDI, &self.data)
     }
 }
 
 #[derive(Debug)]
 pub struct AudioRecordingChunk {
-    size: i32,
     // AESChannelStatusData
     // specified in "AES Recommended Practice for Digital Audio Engineering"
     data: [u8; 24],
@@ -520,25 +755,31 @@ pub struct AudioRecordingChunk {
 
 impl Chunk for AudioRecordingChunk {
     fn parse(buf: Buffer) -> Result<AudioRecordingChunk, ChunkError> {
-        let size = read_i32_be(buf);
+        let size = read_i32_be(buf)?;
         if size != 24 {
             return Err(ChunkError::InvalidSize(24, size));
         }
 
         let mut data = [0; 24];
-        buf.read_exact(&mut data).unwrap();
+        buf.read_exact(&mut data)?;
+
+        Ok(AudioRecordingChunk { data })
+    }
 
-        Ok(AudioRecordingChunk { size, data })
+    fn write(&self, file: &mut impl Write) -> Result<usize, io::Error> {
+        write_padded(file, RECORDING, &self.data)
     }
 }
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum ApplicationSpecificChunk {
     OP1 {
         data: OP1Data,
+        // The order of the keys in the original JSON, used to write them back in the same order
+        key_order: Vec<String>,
     },
     UnknownApplication {
-        size: i32,
         application_signature: ChunkID,
         data: Vec<u8>,
     },
@@ -546,50 +787,44 @@ pub enum ApplicationSpecificChunk {
 
 impl Chunk for ApplicationSpecificChunk {
     fn parse(buf: Buffer) -> Result<ApplicationSpecificChunk, ChunkError> {
-        let size = read_i32_be(buf);
-        let application_signature = read_chunk_id(buf);
-        let mut data = vec![0; (size - 4) as usize]; // account for sig size
-        buf.read_exact(&mut data).unwrap();
+        let size = read_i32_be(buf)?;
+        if size < 4 {
+            return Err(ChunkError::InvalidSize(4, size));
+        }
+        let application_signature = read_chunk_id(buf)?;
+        let data = read_data(buf, size - 4)?; // account for sig size
+
+        if size % 2 > 0 {
+            // if odd, pad byte present - skip it
+            read_u8(buf)?;
+        }
 
         match &application_signature {
             OP_1 => {
                 let end = data.iter().position(|&x| x == 0).unwrap_or(data.len());
-                let ds: Result<OP1Data, _> = serde_json::from_slice(&data[0..end]);
-                match ds {
-                    Ok(data) => Ok(ApplicationSpecificChunk::OP1 { data }),
+                match OP1Data::from_bytes(&data[0..end]) {
+                    Ok((data, key_order)) => Ok(ApplicationSpecificChunk::OP1 { data, key_order }),
                     Err(e) => Err(ChunkError::InvalidData(e.to_string())),
                 }
             }
             _ => Ok(ApplicationSpecificChunk::UnknownApplication {
-                size,
                 application_signature,
-                data: data.iter().map(|byte| u8::from_be_bytes([*byte])).collect(),
+                data,
             }),
         }
     }
 
     fn write(&self, file: &mut impl Write) -> Result<usize, io::Error> {
-        file.write(APPLICATION)?;
-        Ok(match self {
-            Self::OP1 { data } => {
-                let data = data.to_bytes();
-                let size = data.len() + 4;
-                file.write(&(size as i32).to_be_bytes())?;
-                file.write(OP_1)?;
-                file.write(&data)?;
-                size
-            }
+        let (signature, data) = match self {
+            Self::OP1 { data, key_order } => (OP_1, data.to_bytes(key_order)),
             Self::UnknownApplication {
-                size,
                 application_signature,
                 data,
-            } => {
-                file.write(&size.to_be_bytes())?;
-                file.write(application_signature)?;
-                file.write(&data)?;
-                *size as usize
-            }
-        } + 8)
+            } => (application_signature, data.clone()),
+        };
+        let mut chunk = signature.to_vec();
+        chunk.extend(data);
+        write_padded(file, APPLICATION, &chunk)
     }
 }
 
@@ -597,51 +832,243 @@ impl Chunk for ApplicationSpecificChunk {
 pub struct Comment {
     timestamp: u32,
     marker_id: MarkerId,
-    count: u16,
     text: String, // padded to an even # of bytes
 }
 
 impl Comment {
-    // TODO return result
-    pub fn from_reader(r: &mut impl Read) -> Comment {
-        let timestamp = read_u32_be(r);
-        let marker_id = read_i16_be(r);
-        let count = read_u16_be(r);
-
-        let mut str_buf = vec![0; count as usize];
-        r.read_exact(&mut str_buf).unwrap();
-        let text = String::from_utf8(str_buf).unwrap();
+    pub fn from_reader(r: &mut impl Read) -> Result<Comment, ChunkError> {
+        let timestamp = read_u32_be(r)?;
+        let marker_id = read_i16_be(r)?;
+        let count = read_u16_be(r)?;
+        let text = read_string(r, count as usize)?;
+        if count % 2 > 0 {
+            // if odd, pad byte present - skip it
+            read_u8(r)?;
+        }
 
-        Comment {
+        Ok(Comment {
             timestamp,
             marker_id,
-            count,
             text,
+        })
+    }
+
+    fn write(&self, file: &mut impl Write) -> Result<usize, io::Error> {
+        let text = &self.text.as_bytes()[..self.text.len().min(u16::MAX as usize)];
+        file.write_all(&self.timestamp.to_be_bytes())?;
+        file.write_all(&self.marker_id.to_be_bytes())?;
+        file.write_all(&(text.len() as u16).to_be_bytes())?;
+        file.write_all(text)?;
+        if !text.len().is_multiple_of(2) {
+            file.write_all(&[0])?;
+            return Ok(text.len() + 9);
         }
+        Ok(text.len() + 8)
     }
 }
 
 #[derive(Debug)]
 pub struct CommentsChunk {
-    size: i32,
-    num_comments: u16,
     comments: Vec<Comment>,
 }
 
 impl Chunk for CommentsChunk {
     fn parse(buf: Buffer) -> Result<CommentsChunk, ChunkError> {
-        let size = read_i32_be(buf);
-        let num_comments = read_u16_be(buf);
+        let _size = read_i32_be(buf)?;
+        let num_comments = read_u16_be(buf)?;
 
         let mut comments = Vec::with_capacity(num_comments as usize);
         for _ in 0..num_comments {
-            comments.push(Comment::from_reader(buf))
+            comments.push(Comment::from_reader(buf)?)
         }
 
-        Ok(CommentsChunk {
-            size,
-            num_comments,
-            comments,
-        })
+        Ok(CommentsChunk { comments })
+    }
+
+    fn write(&self, file: &mut impl Write) -> Result<usize, io::Error> {
+        let mut cursor = Cursor::new(vec![]);
+        cursor.write_all(&(self.comments.len() as u16).to_be_bytes())?;
+        for comment in self.comments.iter() {
+            comment.write(&mut cursor)?;
+        }
+        write_padded(file, COMMENTS, &cursor.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aiff(common: CommonChunk, sound_data: Vec<u8>) -> Vec<u8> {
+        let form = FormChunk {
+            common,
+            sound: Some(SoundDataChunk {
+                size: sound_data.len() as i32 + 8,
+                offset: 0,
+                block_size: 0,
+                sound_data,
+            }),
+            ..Default::default()
+        };
+        let mut bytes = vec![];
+        form.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn chunk(id: &ChunkID, size: i32, data: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend_from_slice(&size.to_be_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn form(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body = chunks.concat();
+        let mut bytes = FORM.to_vec();
+        bytes.extend_from_slice(&(body.len() as i32 + 4).to_be_bytes());
+        bytes.extend_from_slice(AIFF);
+        bytes.extend(body);
+        bytes
+    }
+
+    #[test]
+    fn frames_follow_the_format() {
+        let form = read_aif(&mut &aiff(Default::default(), vec![0; 8])[..]).unwrap();
+        assert_eq!(form.common.num_sample_frames, 4);
+
+        let stereo = CommonChunk {
+            num_channels: 2,
+            bit_rate: 24,
+            ..Default::default()
+        };
+        let form = read_aif(&mut &aiff(stereo, vec![0; 24])[..]).unwrap();
+        assert_eq!(form.common.num_sample_frames, 4);
+    }
+
+    #[test]
+    fn invalid_formats_are_rejected() {
+        for (num_channels, bit_rate, message) in [
+            (0, 16, "COMM: 0 channels"),
+            (-1, 16, "COMM: -1 channels"),
+            (1, 0, "COMM: 0-bit samples"),
+            (1, 64, "COMM: 64-bit samples"),
+        ] {
+            let common = CommonChunk {
+                num_channels,
+                bit_rate,
+                ..Default::default()
+            };
+            match read_aif(&mut &aiff(common, vec![0; 8])[..]) {
+                Err(ChunkError::InvalidData(e)) => assert_eq!(e, message),
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn sound_data_is_limited_to_the_file() {
+        let mut bytes = aiff(Default::default(), vec![1, 2, 3, 4]);
+        let ssnd = bytes.len() - 4 - 16;
+        assert_eq!(&bytes[ssnd..ssnd + 4], SOUND);
+        bytes[ssnd + 4..ssnd + 8].copy_from_slice(&i32::MAX.to_be_bytes());
+        let form = read_aif(&mut &bytes[..]).unwrap();
+        let sound = form.sound.unwrap();
+        assert_eq!(sound.sound_data, vec![1, 2, 3, 4]);
+        assert_eq!(sound.size, 12);
+    }
+
+    #[test]
+    fn sound_data_shorter_than_its_header_is_rejected() {
+        let bytes = form(&[chunk(COMMON, 18, &[0; 18]), chunk(SOUND, 4, &[0; 4])]);
+        assert!(matches!(
+            read_aif(&mut &bytes[..]),
+            Err(ChunkError::InvalidSize(8, 4))
+        ));
+    }
+
+    #[test]
+    fn scan_skips_pad_bytes() {
+        let bytes = form(&[
+            chunk(NAME, 3, b"abc\0"),
+            chunk(COMMON, 18, &[0; 18]),
+            chunk(SOUND, 8, &[0; 8]),
+        ]);
+        let chunks = scan_chunks(&bytes);
+        let ids: Vec<&ChunkID> = chunks.iter().map(|c| &c.id).collect();
+        assert_eq!(ids, [NAME, COMMON, SOUND]);
+        assert_eq!(chunks[0].offset, 12);
+        assert_eq!(chunks[1].offset, 12 + 8 + 4);
+        assert_eq!(chunks[2].offset, 12 + 8 + 4 + 8 + 18);
+        assert!(chunks.iter().all(|c| c.available == c.size as usize));
+    }
+
+    #[test]
+    fn scan_stops_at_a_truncated_chunk() {
+        let mut bytes = form(&[chunk(COMMON, 18, &[0; 18]), chunk(SOUND, 100, &[0; 10])]);
+        let chunks = scan_chunks(&bytes);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!((chunks[1].size, chunks[1].available), (100, 10));
+
+        bytes.truncate(12 + 26 + 6);
+        let chunks = scan_chunks(&bytes);
+        assert_eq!(chunks.len(), 1);
+    }
+
+    #[test]
+    fn scan_stops_at_a_negative_size() {
+        let bytes = form(&[chunk(NAME, -2, &[]), chunk(COMMON, 18, &[0; 18])]);
+        let chunks = scan_chunks(&bytes);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!((chunks[0].size, chunks[0].available), (-2, 0));
+    }
+
+    #[test]
+    fn odd_sized_chunks_round_trip() {
+        let form = FormChunk {
+            texts: vec![TextChunk {
+                chunk_type: TextChunkType::Name,
+                text: "abc".to_string(),
+            }],
+            midi: vec![MIDIDataChunk {
+                data: vec![1, 2, 3],
+            }],
+            app: vec![ApplicationSpecificChunk::UnknownApplication {
+                application_signature: *b"test",
+                data: vec![4, 5, 6, 7, 8],
+            }],
+            ..Default::default()
+        };
+        let mut bytes = vec![];
+        let size = form.write(&mut bytes).unwrap();
+        assert_eq!(size, bytes.len());
+        assert!(scan_chunks(&bytes)
+            .iter()
+            .all(|c| c.available == c.size as usize));
+
+        let parsed = read_aif(&mut &bytes[..]).unwrap();
+        assert_eq!(parsed.midi[0].data, vec![1, 2, 3]);
+        assert_eq!(parsed.texts[0].text, "abc");
+        match &parsed.app[0] {
+            ApplicationSpecificChunk::UnknownApplication {
+                application_signature,
+                data,
+            } => {
+                assert_eq!(application_signature, b"test");
+                assert_eq!(data, &vec![4, 5, 6, 7, 8]);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let mut rewritten = vec![];
+        parsed.write(&mut rewritten).unwrap();
+        assert_eq!(rewritten, bytes);
+    }
+
+    #[test]
+    fn parse_chunk_supplies_a_missing_pad_byte() {
+        let midi: MIDIDataChunk = parse_chunk(b"MIDI\0\0\0\x03abc").unwrap();
+        assert_eq!(midi.data, b"abc");
+        let midi: MIDIDataChunk = parse_chunk(b"MIDI\0\0\0\x03abc\0").unwrap();
+        assert_eq!(midi.data, b"abc");
     }
 }
@@ -0,0 +1,205 @@
+// Comparing two patches, for the `diff` subcommand.
+
+use crate::chunks::{ApplicationSpecificChunk, Chunk, FormChunk, TextChunkType};
+use crate::keys;
+use crate::op1::{self, OP1Data};
+use serde_json::Value;
+
+/// A field that differs between two patches, with its value in each. `None` means the field is
+/// missing.
+#[derive(Debug)]
+pub struct Difference {
+    pub field: String,
+    pub a: Option<String>,
+    pub b: Option<String>,
+    /// Whether the difference is in the audio, rather than in metadata.
+    pub audio: bool,
+}
+
+/// 64-bit FNV-1a, which is stable between runs and platforms.
+fn hash(bytes: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+fn op_data(form: &FormChunk) -> Option<&OP1Data> {
+    form.app.iter().find_map(|chunk| match chunk {
+        ApplicationSpecificChunk::OP1 { data, .. } => Some(data),
+        _ => None,
+    })
+}
+
+fn sound_data(form: &FormChunk) -> &[u8] {
+    form.sound.as_ref().map_or(&[], |s| &s.sound_data)
+}
+
+/// The fields of drum patches that hold one value per key.
+const KEY_FIELDS: &[&str] = &["start", "end", "pitch", "reverse", "volume", "playmode"];
+
+fn diff_values(field: &str, a: Option<&Value>, b: Option<&Value>, r: &mut Vec<Difference>) {
+    match (a, b) {
+        (Some(Value::Object(a)), Some(Value::Object(b))) => {
+            let names = a.keys().chain(b.keys().filter(|k| !a.contains_key(*k)));
+            for name in names {
+                let field = if field.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}.{}", field, name)
+                };
+                diff_values(&field, a.get(name), b.get(name), r);
+            }
+        }
+        (Some(Value::Array(a)), Some(Value::Array(b))) if a.len() == b.len() => {
+            let by_key = a.len() == keys::NUM_KEYS as usize && KEY_FIELDS.contains(&field);
+            for (i, (a, b)) in a.iter().zip(b.iter()).enumerate() {
+                let label = if by_key {
+                    keys::key_name(i as u8 + 1)
+                } else {
+                    i.to_string()
+                };
+                diff_values(&format!("{}[{}]", field, label), Some(a), Some(b), r);
+            }
+        }
+        (a, b) if a != b => r.push(Difference {
+            field: field.to_string(),
+            a: a.map(|v| v.to_string()),
+            b: b.map(|v| v.to_string()),
+            audio: false,
+        }),
+        _ => (),
+    }
+}
+
+/// The audio of each drum key, or of the whole sample.
+fn audio_regions<'a>(data: Option<&OP1Data>, sound: &'a [u8]) -> Vec<(String, &'a [u8])> {
+    match data {
+        Some(OP1Data::Drum { start, end, .. }) => (0..keys::NUM_KEYS as usize)
+            .map(|i| {
+                let offset =
+                    |position: u32| ((position / op1::POSITION_PER_BYTE) as usize).min(sound.len());
+                let (start, end) = (offset(start[i]), offset(end[i]));
+                let label = format!("audio[{}]", keys::key_name(i as u8 + 1));
+                (label, &sound[start.min(end)..end])
+            })
+            .collect(),
+        _ => vec![("audio".to_string(), sound)],
+    }
+}
+
+/// Every chunk other than the OP metadata and the sound data, as it would be written.
+fn other_chunks(form: &FormChunk) -> Vec<(String, Vec<u8>)> {
+    fn bytes(chunk: &impl Chunk) -> Vec<u8> {
+        let mut bytes = vec![];
+        chunk.write(&mut bytes).unwrap();
+        bytes
+    }
+    let mut chunks = vec![("COMM".to_string(), bytes(&form.common))];
+    for chunk in form.app.iter() {
+        if let ApplicationSpecificChunk::UnknownApplication {
+            application_signature,
+            ..
+        } = chunk
+        {
+            let signature = String::from_utf8_lossy(application_signature);
+            chunks.push((format!("APPL {}", signature), bytes(chunk)));
+        }
+    }
+    for (i, chunk) in form.midi.iter().enumerate() {
+        chunks.push((format!("MIDI[{}]", i), bytes(chunk)));
+    }
+    for (i, chunk) in form.texts.iter().enumerate() {
+        let id = match chunk.chunk_type {
+            TextChunkType::Name => "NAME",
+            TextChunkType::Author => "AUTH",
+            TextChunkType::Copyright => "(c) ",
+            TextChunkType::Annotation => "ANNO",
+        };
+        chunks.push((format!("{}[{}]", id, i), bytes(chunk)));
+    }
+    if let Some(chunk) = &form.comments {
+        chunks.push(("COMT".to_string(), bytes(chunk)));
+    }
+    if let Some(chunk) = &form.instrument {
+        chunks.push(("INST".to_string(), bytes(chunk)));
+    }
+    if let Some(chunk) = &form.recording {
+        chunks.push(("AESD".to_string(), bytes(chunk)));
+    }
+    if let Some(chunk) = &form.markers {
+        chunks.push(("MARK".to_string(), bytes(chunk)));
+    }
+    chunks
+}
+
+/// Compare two patches: their OP metadata field by field, the audio of each key (or of the
+/// whole sample) by hash, and their other chunks.
+pub fn diff(a: &FormChunk, b: &FormChunk) -> Vec<Difference> {
+    let mut r = vec![];
+    let (data_a, data_b) = (op_data(a), op_data(b));
+    let json = |data: Option<&OP1Data>| data.map(|d| serde_json::to_value(d).unwrap());
+    diff_values(
+        "",
+        json(data_a).as_ref().or(Some(&Value::Null)),
+        json(data_b).as_ref().or(Some(&Value::Null)),
+        &mut r,
+    );
+
+    let regions_a = audio_regions(data_a, sound_data(a));
+    let regions_b = audio_regions(data_b, sound_data(b));
+    if regions_a.len() == regions_b.len() {
+        for ((label, a), (_, b)) in regions_a.iter().zip(regions_b.iter()) {
+            let (a, b) = (hash(a), hash(b));
+            if a != b {
+                r.push(Difference {
+                    field: label.clone(),
+                    a: Some(a),
+                    b: Some(b),
+                    audio: true,
+                });
+            }
+        }
+    } else {
+        let (a, b) = (hash(sound_data(a)), hash(sound_data(b)));
+        if a != b {
+            r.push(Difference {
+                field: "audio".to_string(),
+                a: Some(a),
+                b: Some(b),
+                audio: true,
+            });
+        }
+    }
+
+    let (chunks_a, chunks_b) = (other_chunks(a), other_chunks(b));
+    let names = chunks_a
+        .iter()
+        .chain(
+            chunks_b
+                .iter()
+                .filter(|(n, _)| !chunks_a.iter().any(|(m, _)| m == n)),
+        )
+        .map(|(name, _)| name);
+    for name in names {
+        let find = |chunks: &[(String, Vec<u8>)]| {
+            chunks
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, bytes)| bytes.clone())
+        };
+        let (a, b) = (find(&chunks_a), find(&chunks_b));
+        if a != b {
+            r.push(Difference {
+                field: name.clone(),
+                a: a.map(|bytes| format!("{} bytes, {}", bytes.len(), hash(&bytes))),
+                b: b.map(|bytes| format!("{} bytes, {}", bytes.len(), hash(&bytes))),
+                // The format of the sound data
+                audio: name == "COMM",
+            });
+        }
+    }
+    r
+}
@@ -0,0 +1,181 @@
+// A human-readable summary of a patch, for the `info` subcommand.
+
+use crate::chunks::{ApplicationSpecificChunk, FormChunk};
+use crate::keys;
+use crate::op1::{self, Device, OP1Data};
+use std::io::{self, Write};
+
+/// A 0-1 parameter, as taken by `fx`, `lfo` and `knobs`.
+fn param(value: u16) -> String {
+    format!("{:.2}", value as f32 / op1::MAX_PARAM as f32)
+}
+
+fn ms(ms: f32) -> String {
+    if ms < 1000.0 {
+        format!("{:.0}ms", ms)
+    } else {
+        format!("{:.2}s", ms / 1000.0)
+    }
+}
+
+fn db(volume: u16) -> String {
+    let db = op1::volume_to_db(volume as f32);
+    if db.is_finite() {
+        format!("{:+.1}dB", db)
+    } else {
+        "-inf".to_string()
+    }
+}
+
+/// Parameters by name. The raw values are shown for effects this tool doesn't know about.
+fn params(names: &[&str], values: &[u16; 8]) -> String {
+    if names.is_empty() {
+        return values
+            .iter()
+            .map(|value| value.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+    }
+    names
+        .iter()
+        .zip(values.iter())
+        .map(|(name, &value)| format!("{} {}", name, param(value)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn on_off(active: bool) -> &'static str {
+    if active {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Write a summary of `form`: its type, name, length against the limits of `device` and format,
+/// its effects, and the settings of each key or of the synth.
+pub fn write_info(w: &mut impl Write, form: &FormChunk, device: Device) -> io::Result<()> {
+    let data = match form.app.first() {
+        Some(ApplicationSpecificChunk::OP1 { data, .. }) => data,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "No OP data found",
+            ))
+        }
+    };
+    let sample_rate = form.common.sample_rate_hz();
+    let bytes_per_frame = form.common.bytes_per_frame();
+    let bytes = form.sound.as_ref().map_or(0, |s| s.sound_data.len());
+    let seconds = if sample_rate > 0.0 {
+        (bytes / bytes_per_frame) as f64 / sample_rate
+    } else {
+        0.0
+    };
+    let max_seconds = match data {
+        OP1Data::Drum { .. } => device.max_drum_seconds(),
+        _ => device.max_synth_seconds(),
+    };
+
+    writeln!(w, "Type:     {}", data.engine())?;
+    writeln!(w, "Name:     {}", data.name())?;
+    // The stored octave is relative to the default octave, 5 on the 1-10 scale of `--octave`
+    writeln!(w, "Octave:   {}", data.octave() as i32 + 5)?;
+    writeln!(
+        w,
+        "Length:   {:.2}s of {}s ({:.0}%)",
+        seconds,
+        max_seconds,
+        seconds / max_seconds as f64 * 100.0
+    )?;
+    writeln!(
+        w,
+        "Format:   {} Hz, {}-bit, {}",
+        sample_rate,
+        form.common.bit_rate,
+        match form.common.num_channels {
+            1 => "mono".to_string(),
+            2 => "stereo".to_string(),
+            n => format!("{} channels", n),
+        }
+    )?;
+
+    let (fx_active, fx_type, fx_params) = data.fx();
+    writeln!(
+        w,
+        "FX:       {} ({}): {}",
+        fx_type,
+        on_off(*fx_active),
+        params(fx_type.param_names(), fx_params)
+    )?;
+    let (lfo_active, lfo_type, lfo_params) = data.lfo();
+    writeln!(
+        w,
+        "LFO:      {} ({}): {}",
+        lfo_type,
+        on_off(*lfo_active),
+        params(lfo_type.param_names(), lfo_params)
+    )?;
+
+    if let Some(adsr) = data.adsr() {
+        writeln!(
+            w,
+            "Envelope: attack {}, decay {}, sustain {}, release {}",
+            ms(op1::envelope_to_ms(adsr[0])),
+            ms(op1::envelope_to_ms(adsr[1])),
+            param(adsr[2]),
+            ms(op1::envelope_to_ms(adsr[3]))
+        )?;
+    }
+
+    match data {
+        OP1Data::Sampler {
+            base_freq, knobs, ..
+        } => {
+            writeln!(w, "Base:     {} Hz", base_freq)?;
+            writeln!(w, "Knobs:    {}", params(op1::SAMPLER_KNOB_NAMES, knobs))?;
+        }
+        OP1Data::Drum {
+            start,
+            end,
+            pitch,
+            reverse,
+            volume,
+            playmode,
+            ..
+        } => {
+            writeln!(w)?;
+            writeln!(
+                w,
+                "{:>3}  {:<4}  {:>8}  {:>9}  {:>7}  {:<9}  PLAYMODE",
+                "KEY", "NOTE", "LENGTH", "PITCH", "VOLUME", "DIRECTION"
+            )?;
+            for i in 0..keys::NUM_KEYS as usize {
+                let frames = (end[i].saturating_sub(start[i]) / op1::POSITION_PER_BYTE) as usize
+                    / bytes_per_frame;
+                let length = if frames > 0 && sample_rate > 0.0 {
+                    ms((frames as f64 / sample_rate * 1000.0) as f32)
+                } else {
+                    "-".to_string()
+                };
+                writeln!(
+                    w,
+                    "{:>3}  {:<4}  {:>8}  {:>+7.2}st  {:>7}  {:<9}  {}",
+                    i + 1,
+                    keys::key_name(i as u8 + 1),
+                    length,
+                    pitch[i] as f32 / op1::PITCH_STEPS_PER_SEMITONE,
+                    db(volume[i]),
+                    if reverse[i] > 8192 {
+                        "reverse"
+                    } else {
+                        "forward"
+                    },
+                    playmode[i]
+                )?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
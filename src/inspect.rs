@@ -0,0 +1,269 @@
+// The chunk structure of an AIFF file, for the `inspect` subcommand. Unlike `FormChunk::parse`,
+// this reads each chunk on its own, so that as much as possible can be shown of broken files.
+
+use crate::chunks::{self, parse_chunk, CommonChunk, InstrumentChunk, Loop, MarkerChunk};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::io::{self, Write};
+
+#[derive(Debug, Serialize)]
+pub struct ChunkInfo {
+    pub id: String,
+    /// The offset of the chunk's ID from the start of the file.
+    pub offset: usize,
+    /// The size the chunk declares.
+    pub size: i32,
+    /// How many bytes of the chunk's data are present.
+    pub actual_size: usize,
+    /// Whether the chunk has an odd size, and so should be followed by a pad byte.
+    pub padded: bool,
+    /// The pad byte, if there is one.
+    pub pad_byte: Option<u8>,
+    /// The decoded header of the chunk, for the chunks that are understood.
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub fields: Map<String, Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Inspection {
+    pub form_type: String,
+    pub size: i32,
+    pub actual_size: usize,
+    pub chunks: Vec<ChunkInfo>,
+    /// Bytes after the last chunk that don't make up a chunk header.
+    pub trailing_bytes: usize,
+}
+
+fn loop_fields(l: &Loop) -> Value {
+    json!({
+        "play_mode": l.play_mode,
+        "begin_loop": l.begin_loop,
+        "end_loop": l.end_loop,
+    })
+}
+
+fn u32_at(data: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]])
+}
+
+/// Decode the header of a chunk, given its complete bytes including its ID.
+fn fields(id: &chunks::ChunkID, chunk: &[u8]) -> Map<String, Value> {
+    let data = &chunk[8..];
+    let value = match id {
+        chunks::COMMON => match parse_chunk::<CommonChunk>(chunk) {
+            Ok(common) => json!({
+                "num_channels": common.num_channels,
+                "num_sample_frames": common.num_sample_frames,
+                "sample_size": common.bit_rate,
+                "sample_rate": common.sample_rate_hz(),
+            }),
+            Err(e) => json!({ "error": e.to_string() }),
+        },
+        chunks::SOUND if data.len() >= 8 => json!({
+            "offset": u32_at(data, 0),
+            "block_size": u32_at(data, 4),
+            "sound_data_bytes": data.len() - 8,
+        }),
+        chunks::MARKER => match parse_chunk::<MarkerChunk>(chunk) {
+            Ok(mark) => json!({
+                "num_markers": mark.num_markers,
+                "markers": mark
+                    .markers
+                    .iter()
+                    .map(|m| json!({"id": m.id, "position": m.position, "name": m.marker_name}))
+                    .collect::<Vec<_>>(),
+            }),
+            Err(e) => json!({ "error": e.to_string() }),
+        },
+        chunks::INSTRUMENT => match parse_chunk::<InstrumentChunk>(chunk) {
+            Ok(inst) => json!({
+                "base_note": inst.base_note,
+                "detune": inst.detune,
+                "low_note": inst.low_note,
+                "high_note": inst.high_note,
+                "low_velocity": inst.low_velocity,
+                "high_velocity": inst.high_velocity,
+                "gain": inst.gain,
+                "sustain_loop": loop_fields(&inst.sustain_loop),
+                "release_loop": loop_fields(&inst.release_loop),
+            }),
+            Err(e) => json!({ "error": e.to_string() }),
+        },
+        chunks::APPLICATION if data.len() >= 4 => {
+            let signature = String::from_utf8_lossy(&data[0..4]).to_string();
+            if &data[0..4] == chunks::OP_1 {
+                let json = &data[4..];
+                let end = json.iter().position(|&x| x == 0).unwrap_or(json.len());
+                json!({
+                    "signature": signature,
+                    "json": String::from_utf8_lossy(&json[..end]),
+                })
+            } else {
+                json!({ "signature": signature })
+            }
+        }
+        _ => Value::Null,
+    };
+    match value {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    }
+}
+
+/// List the chunks of the AIFF file in `bytes`, following their declared sizes as far as the
+/// data allows.
+pub fn inspect(bytes: &[u8]) -> Result<Inspection, String> {
+    if bytes.len() < 12 || &bytes[0..4] != chunks::FORM {
+        return Err("Not an AIFF file".to_string());
+    }
+    let mut end = 12;
+    let chunks = chunks::scan_chunks(bytes)
+        .into_iter()
+        .map(|header| {
+            let chunk_end = header.offset + 8 + header.available;
+            let padded = header.size > 0 && header.size % 2 == 1;
+            let pad_byte = if padded && header.available == header.size as usize {
+                bytes.get(chunk_end).copied()
+            } else {
+                None
+            };
+            end = chunk_end + pad_byte.map_or(0, |_| 1);
+            ChunkInfo {
+                id: String::from_utf8_lossy(&header.id).to_string(),
+                offset: header.offset,
+                size: header.size,
+                actual_size: header.available,
+                padded,
+                pad_byte,
+                fields: fields(&header.id, &bytes[header.offset..chunk_end]),
+            }
+        })
+        .collect();
+
+    Ok(Inspection {
+        form_type: String::from_utf8_lossy(&bytes[8..12]).to_string(),
+        size: i32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        actual_size: bytes.len() - 8,
+        chunks,
+        trailing_bytes: bytes.len() - end,
+    })
+}
+
+fn write_fields(w: &mut impl Write, fields: &Map<String, Value>, indent: usize) -> io::Result<()> {
+    for (name, value) in fields.iter() {
+        match value {
+            Value::Object(map) => {
+                writeln!(w, "{:indent$}{}:", "", name, indent = indent)?;
+                write_fields(w, map, indent + 2)?;
+            }
+            Value::Array(values) => {
+                writeln!(w, "{:indent$}{}:", "", name, indent = indent)?;
+                for value in values.iter() {
+                    writeln!(w, "{:indent$}- {}", "", value, indent = indent + 2)?;
+                }
+            }
+            Value::String(s) => writeln!(w, "{:indent$}{}: {}", "", name, s, indent = indent)?,
+            _ => writeln!(w, "{:indent$}{}: {}", "", name, value, indent = indent)?,
+        }
+    }
+    Ok(())
+}
+
+pub fn write_text(w: &mut impl Write, inspection: &Inspection) -> io::Result<()> {
+    writeln!(
+        w,
+        "FORM {}  offset 0  size {}  actual {}",
+        inspection.form_type, inspection.size, inspection.actual_size
+    )?;
+    for chunk in inspection.chunks.iter() {
+        let pad = match (chunk.padded, chunk.pad_byte) {
+            (false, _) => String::new(),
+            (true, Some(byte)) => format!("  pad 0x{:02x}", byte),
+            (true, None) => "  pad missing".to_string(),
+        };
+        writeln!(
+            w,
+            "{}  offset {}  size {}  actual {}{}",
+            chunk.id, chunk.offset, chunk.size, chunk.actual_size, pad
+        )?;
+        write_fields(w, &chunk.fields, 2)?;
+    }
+    if inspection.trailing_bytes > 0 {
+        writeln!(w, "{} trailing bytes", inspection.trailing_bytes)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunks::{Chunk, FormChunk, SoundDataChunk};
+
+    fn patch() -> Vec<u8> {
+        let form = FormChunk {
+            sound: Some(SoundDataChunk {
+                size: 12,
+                offset: 0,
+                block_size: 0,
+                sound_data: vec![0; 4],
+            }),
+            ..Default::default()
+        };
+        let mut bytes = vec![];
+        form.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn lists_chunks_and_fields() {
+        let bytes = patch();
+        let inspection = inspect(&bytes).unwrap();
+        assert_eq!(inspection.form_type, "AIFF");
+        assert_eq!(inspection.size as usize, inspection.actual_size);
+        let ids: Vec<&str> = inspection.chunks.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, ["COMM", "SSND"]);
+        let common = &inspection.chunks[0].fields;
+        assert_eq!(common["num_channels"], 1);
+        assert_eq!(common["sample_rate"], 44100.0);
+        assert_eq!(inspection.chunks[1].fields["sound_data_bytes"], 4);
+        assert_eq!(inspection.trailing_bytes, 0);
+    }
+
+    #[test]
+    fn shows_broken_chunks() {
+        let mut bytes = patch();
+        // Zero channels, which `FormChunk::parse` rejects, are still shown
+        bytes[20..22].copy_from_slice(&0i16.to_be_bytes());
+        bytes.truncate(bytes.len() - 2);
+        let inspection = inspect(&bytes).unwrap();
+        assert_eq!(inspection.chunks[0].fields["num_channels"], 0);
+        assert_eq!(inspection.chunks[1].size, 12);
+        assert_eq!(inspection.chunks[1].actual_size, 10);
+        assert_eq!(inspection.chunks[1].fields["sound_data_bytes"], 2);
+        assert_eq!(inspection.trailing_bytes, 0);
+
+        let mut bytes = patch();
+        bytes.extend_from_slice(&[0; 3]);
+        assert_eq!(inspect(&bytes).unwrap().trailing_bytes, 3);
+    }
+
+    #[test]
+    fn reports_pad_bytes() {
+        let mut bytes = patch();
+        bytes.extend_from_slice(b"NAME\0\0\0\x03abc\x00");
+        let inspection = inspect(&bytes).unwrap();
+        let name = &inspection.chunks[2];
+        assert!(name.padded);
+        assert_eq!(name.pad_byte, Some(0));
+
+        bytes.pop();
+        let inspection = inspect(&bytes).unwrap();
+        assert_eq!(inspection.chunks[2].pad_byte, None);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(inspect(b"RIFF\0\0\0\x04WAVE").is_err());
+        assert!(inspect(b"FORM").is_err());
+    }
+}
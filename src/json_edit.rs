@@ -0,0 +1,383 @@
+// Editing the OP metadata as JSON, for `set`: merging in partial objects (RFC 7396), applying
+// JSON Patch documents (RFC 6902), and assigning fields by JSON Pointer (RFC 6901).
+
+use serde_json::{Map, Value};
+
+/// Split a JSON Pointer into its unescaped reference tokens.
+fn parse_pointer(pointer: &str) -> Result<Vec<String>, String> {
+    if pointer.is_empty() {
+        return Ok(vec![]);
+    }
+    if !pointer.starts_with('/') {
+        return Err(format!(
+            "Invalid JSON pointer {:?}: must start with `/`",
+            pointer
+        ));
+    }
+    Ok(pointer[1..]
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+/// An array index token: digits without leading zeros, below `len`.
+fn array_index(token: &str, len: usize, pointer: &str) -> Result<usize, String> {
+    let canonical =
+        token.chars().all(|c| c.is_ascii_digit()) && (token == "0" || !token.starts_with('0'));
+    match token.parse::<usize>() {
+        Ok(i) if i < len && canonical => Ok(i),
+        _ => Err(format!("Invalid array index {:?} in {}", token, pointer)),
+    }
+}
+
+fn get_mut<'a>(
+    value: &'a mut Value,
+    tokens: &[String],
+    pointer: &str,
+) -> Result<&'a mut Value, String> {
+    let mut value = value;
+    for token in tokens.iter() {
+        value = match value {
+            Value::Object(map) => map
+                .get_mut(token)
+                .ok_or_else(|| format!("No field {:?} in {}", token, pointer))?,
+            Value::Array(array) => {
+                let i = array_index(token, array.len(), pointer)?;
+                &mut array[i]
+            }
+            _ => return Err(format!("{} does not exist", pointer)),
+        };
+    }
+    Ok(value)
+}
+
+/// The parent of the value `pointer` refers to, and the last token of `pointer`.
+fn parent_mut<'a>(value: &'a mut Value, pointer: &str) -> Result<(&'a mut Value, String), String> {
+    let mut tokens = parse_pointer(pointer)?;
+    let last = tokens
+        .pop()
+        .ok_or_else(|| "Cannot change the whole document".to_string())?;
+    Ok((get_mut(value, &tokens, pointer)?, last))
+}
+
+/// Add `new` at `pointer`, replacing the whole document if `pointer` is empty.
+fn add(value: &mut Value, pointer: &str, new: Value) -> Result<(), String> {
+    if pointer.is_empty() {
+        *value = new;
+        return Ok(());
+    }
+    let (parent, last) = parent_mut(value, pointer)?;
+    match parent {
+        Value::Object(map) => {
+            map.insert(last, new);
+        }
+        Value::Array(array) if last == "-" => array.push(new),
+        Value::Array(array) => {
+            let i = array_index(&last, array.len() + 1, pointer)?;
+            array.insert(i, new);
+        }
+        _ => return Err(format!("{} does not exist", pointer)),
+    }
+    Ok(())
+}
+
+fn remove(value: &mut Value, pointer: &str) -> Result<Value, String> {
+    let (parent, last) = parent_mut(value, pointer)?;
+    match parent {
+        Value::Object(map) => map
+            .remove(&last)
+            .ok_or_else(|| format!("{} does not exist", pointer)),
+        Value::Array(array) => {
+            let i = array_index(&last, array.len(), pointer)?;
+            Ok(array.remove(i))
+        }
+        _ => Err(format!("{} does not exist", pointer)),
+    }
+}
+
+/// Merge `patch` into `target`: objects are merged recursively (into an empty object if the
+/// existing value is not one), `null` removes a field, and anything else replaces the existing
+/// value.
+pub fn merge(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+            let target = target.as_object_mut().unwrap();
+            for (name, value) in patch.iter() {
+                if value.is_null() {
+                    target.remove(name);
+                } else {
+                    merge(target.entry(name.clone()).or_insert(Value::Null), value);
+                }
+            }
+        }
+        patch => *target = patch.clone(),
+    }
+}
+
+/// Apply a JSON Patch document: an array of `add`, `remove`, `replace`, `move`, `copy` and
+/// `test` operations.
+pub fn apply_patch(target: &mut Value, patch: &[Value]) -> Result<(), String> {
+    for (i, operation) in patch.iter().enumerate() {
+        let field = |name: &str| {
+            operation
+                .get(name)
+                .ok_or_else(|| format!("Operation {} has no `{}`", i + 1, name))
+        };
+        let pointer = |name: &str| {
+            field(name)?
+                .as_str()
+                .ok_or_else(|| format!("Operation {}: `{}` is not a string", i + 1, name))
+        };
+        let path = pointer("path")?;
+        let result = match pointer("op")? {
+            "add" => add(target, path, field("value")?.clone()),
+            "remove" => remove(target, path).map(|_| ()),
+            "replace" => {
+                let value = field("value")?.clone();
+                get_mut(target, &parse_pointer(path)?, path).map(|existing| *existing = value)
+            }
+            "move" => {
+                let from = pointer("from")?;
+                if path.starts_with(&format!("{}/", from)) {
+                    Err(format!("Cannot move {} into itself", from))
+                } else {
+                    remove(target, from).and_then(|value| add(target, path, value))
+                }
+            }
+            "copy" => {
+                let from = pointer("from")?;
+                get_mut(target, &parse_pointer(from)?, from)
+                    .map(|value| value.clone())
+                    .and_then(|value| add(target, path, value))
+            }
+            "test" => {
+                let expected = field("value")?;
+                get_mut(target, &parse_pointer(path)?, path).and_then(|value| {
+                    if value == expected {
+                        Ok(())
+                    } else {
+                        Err(format!(
+                            "Test failed: {} is {}, not {}",
+                            path, value, expected
+                        ))
+                    }
+                })
+            }
+            op => Err(format!("Unknown operation {:?}", op)),
+        };
+        result.map_err(|e| format!("Operation {}: {}", i + 1, e))?;
+    }
+    Ok(())
+}
+
+/// Assign a field from `POINTER=VALUE`, e.g. `/fx_params/3=12000`. The value is parsed as JSON
+/// if possible, and is otherwise taken as a string.
+pub fn set_field(target: &mut Value, assignment: &str) -> Result<(), String> {
+    let (pointer, value) = assignment.split_once('=').ok_or_else(|| {
+        format!(
+            "Invalid field assignment {:?}: expected POINTER=VALUE",
+            assignment
+        )
+    })?;
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+    match get_mut(target, &parse_pointer(pointer)?, pointer) {
+        Ok(existing) => *existing = value,
+        Err(_) => add(target, pointer, value)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn patched(target: Value, patch: Value) -> Result<Value, String> {
+        let mut target = target;
+        apply_patch(&mut target, patch.as_array().unwrap())?;
+        Ok(target)
+    }
+
+    #[test]
+    fn merge_objects() {
+        let mut target = json!({"a": 1, "b": {"c": 2, "d": 3}, "e": [1, 2]});
+        merge(
+            &mut target,
+            &json!({"a": null, "b": {"c": 4, "f": 5}, "e": [3], "g": {"h": null}}),
+        );
+        assert_eq!(
+            target,
+            json!({"b": {"c": 4, "d": 3, "f": 5}, "e": [3], "g": {}})
+        );
+    }
+
+    #[test]
+    fn merge_replaces_non_objects() {
+        let mut target = json!({"a": 1});
+        merge(&mut target, &json!([1, 2]));
+        assert_eq!(target, json!([1, 2]));
+        let mut target = json!({"a": [1, 2]});
+        merge(&mut target, &json!({"a": {"b": 1}}));
+        assert_eq!(target, json!({"a": {"b": 1}}));
+    }
+
+    #[test]
+    fn patch_operations() {
+        let target = json!({"a": 1, "b": [1, 2, 3], "c": {"d": 4}});
+        let patch = json!([
+            {"op": "add", "path": "/e", "value": 5},
+            {"op": "remove", "path": "/b/0"},
+            {"op": "replace", "path": "/a", "value": 10},
+            {"op": "move", "from": "/c/d", "path": "/f"},
+            {"op": "copy", "from": "/b", "path": "/c/b"},
+            {"op": "test", "path": "/c/b", "value": [2, 3]},
+        ]);
+        assert_eq!(
+            patched(target, patch),
+            Ok(json!({"a": 10, "b": [2, 3], "c": {"b": [2, 3]}, "e": 5, "f": 4}))
+        );
+    }
+
+    #[test]
+    fn patch_arrays() {
+        let target = json!({"a": [1, 2]});
+        let patch = json!([
+            {"op": "add", "path": "/a/-", "value": 3},
+            {"op": "add", "path": "/a/0", "value": 0},
+            {"op": "add", "path": "/a/4", "value": 4},
+        ]);
+        assert_eq!(patched(target, patch), Ok(json!({"a": [0, 1, 2, 3, 4]})));
+        assert!(patched(json!([1]), json!([{"op": "add", "path": "/2", "value": 0}])).is_err());
+        assert!(patched(json!([1]), json!([{"op": "remove", "path": "/-"}])).is_err());
+    }
+
+    #[test]
+    fn patch_rejects_leading_zeros() {
+        let target = json!([1, 2]);
+        assert!(patched(target.clone(), json!([{"op": "remove", "path": "/01"}])).is_err());
+        assert!(patched(
+            target.clone(),
+            json!([{"op": "test", "path": "/00", "value": 1}])
+        )
+        .is_err());
+        assert!(patched(target.clone(), json!([{"op": "remove", "path": "/+1"}])).is_err());
+        assert_eq!(
+            patched(target, json!([{"op": "remove", "path": "/0"}])),
+            Ok(json!([2]))
+        );
+    }
+
+    #[test]
+    fn patch_applies_in_order() {
+        let target = json!({"a": 1});
+        let patch = json!([
+            {"op": "replace", "path": "/a", "value": 2},
+            {"op": "test", "path": "/a", "value": 2},
+            {"op": "copy", "from": "/a", "path": "/b"},
+            {"op": "remove", "path": "/a"},
+        ]);
+        assert_eq!(patched(target.clone(), patch), Ok(json!({"b": 2})));
+
+        let patch = json!([
+            {"op": "add", "path": "/b", "value": 2},
+            {"op": "test", "path": "/a", "value": 2},
+            {"op": "add", "path": "/c", "value": 3},
+        ]);
+        let mut value = target;
+        let error = apply_patch(&mut value, patch.as_array().unwrap()).unwrap_err();
+        assert!(error.starts_with("Operation 2:"), "{}", error);
+        assert_eq!(value, json!({"a": 1, "b": 2}));
+    }
+
+    #[test]
+    fn patch_whole_document() {
+        let target = json!({"a": 1});
+        assert_eq!(
+            patched(
+                target.clone(),
+                json!([{"op": "add", "path": "", "value": [1]}])
+            ),
+            Ok(json!([1]))
+        );
+        assert_eq!(
+            patched(
+                target.clone(),
+                json!([{"op": "replace", "path": "", "value": 2}])
+            ),
+            Ok(json!(2))
+        );
+        assert_eq!(
+            patched(
+                target.clone(),
+                json!([{"op": "copy", "from": "/a", "path": ""}])
+            ),
+            Ok(json!(1))
+        );
+        assert!(patched(target, json!([{"op": "remove", "path": ""}])).is_err());
+    }
+
+    #[test]
+    fn patch_errors() {
+        let target = json!({"a": {"b": 1}});
+        assert!(patched(
+            target.clone(),
+            json!([{"op": "move", "from": "/a", "path": "/a/c"}])
+        )
+        .is_err());
+        assert!(patched(target.clone(), json!([{"op": "remove", "path": "/x"}])).is_err());
+        assert!(patched(
+            target.clone(),
+            json!([{"op": "replace", "path": "/x", "value": 1}])
+        )
+        .is_err());
+        assert!(patched(
+            target.clone(),
+            json!([{"op": "add", "path": "/x/y", "value": 1}])
+        )
+        .is_err());
+        assert!(patched(
+            target.clone(),
+            json!([{"op": "add", "path": "x", "value": 1}])
+        )
+        .is_err());
+        assert!(patched(target.clone(), json!([{"op": "add", "path": "/x"}])).is_err());
+        assert!(patched(target, json!([{"op": "frob", "path": "/a"}])).is_err());
+    }
+
+    #[test]
+    fn patch_escaped_pointers() {
+        let target = json!({"a/b": 1, "c~d": 2});
+        let patch = json!([
+            {"op": "test", "path": "/a~1b", "value": 1},
+            {"op": "move", "from": "/c~0d", "path": "/e"},
+        ]);
+        assert_eq!(patched(target, patch), Ok(json!({"a/b": 1, "e": 2})));
+    }
+
+    #[test]
+    fn set_fields() {
+        let mut target = json!({"name": "x", "params": [1, 2, 3]});
+        set_field(&mut target, "/params/1=20").unwrap();
+        set_field(&mut target, "/name=bass").unwrap();
+        set_field(&mut target, "/stereo=true").unwrap();
+        set_field(&mut target, "/params/-=4").unwrap();
+        set_field(&mut target, "/text=a=b").unwrap();
+        assert_eq!(
+            target,
+            json!({"name": "bass", "params": [1, 20, 3, 4], "stereo": true, "text": "a=b"})
+        );
+    }
+
+    #[test]
+    fn set_field_errors() {
+        let mut target = json!({"params": [1, 2]});
+        assert!(set_field(&mut target, "/params/1").is_err());
+        assert!(set_field(&mut target, "params=1").is_err());
+        assert!(set_field(&mut target, "/params/5=1").is_err());
+        assert!(set_field(&mut target, "/missing/field=1").is_err());
+        assert_eq!(target, json!({"params": [1, 2]}));
+    }
+}
@@ -0,0 +1,280 @@
+// Key selectors for the 24 keys of the OP-1, and note names.
+//
+// Keys are numbered 1-24 from the lowest F to the highest E. Key names are relative to this
+// layout: the first octave of keys runs from F1 to E1 and the second from F2 to E2. Notes, such
+// as those in sample filenames, are named in scientific pitch notation, where middle C (MIDI note
+// 60) is C4.
+
+pub const NUM_KEYS: u8 = 24;
+
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Split a note name such as `F#3` or `Bb2` into the semitone of its letter above C, its
+/// accidental and its octave.
+fn split_note(s: &str) -> Option<(i32, i32, i32)> {
+    let mut chars = s.chars().peekable();
+    let letter = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let accidental = match chars.peek() {
+        Some('#') => 1,
+        Some('b') => -1,
+        _ => 0,
+    };
+    if accidental != 0 {
+        chars.next();
+    }
+    let octave: i32 = chars.collect::<String>().parse().ok()?;
+    Some((letter, accidental, octave))
+}
+
+/// The name of a MIDI note, e.g. `60` -> `C4`.
+pub fn note_name(note: u8) -> String {
+    format!("{}{}", NOTE_NAMES[note as usize % 12], note as i32 / 12 - 1)
+}
+
+/// Parse a note name such as `C4`, `F#3` or `Bb2` into a MIDI note.
+pub fn parse_note(s: &str) -> Option<u8> {
+    let (letter, accidental, octave) = split_note(s)?;
+    let note = (octave + 1) * 12 + letter + accidental;
+    if (0..=127).contains(&note) {
+        Some(note as u8)
+    } else {
+        None
+    }
+}
+
+/// The MIDI note played by a key at the default octave, where key 1 is F3.
+pub fn midi_note(key: u8) -> u8 {
+    52 + key
+}
+
+/// The name of a key, e.g. `1` -> `F1`, `20` -> `C2`, `21` -> `C#2`.
+pub fn key_name(key: u8) -> String {
+    format!(
+        "{}{}",
+        NOTE_NAMES[midi_note(key) as usize % 12],
+        (key as usize - 1) / 12 + 1
+    )
+}
+
+pub fn is_black(key: u8) -> bool {
+    NOTE_NAMES[midi_note(key) as usize % 12].ends_with('#')
+}
+
+fn check_range(key: i32, s: &str) -> Result<u8, String> {
+    if key < 1 || key > NUM_KEYS as i32 {
+        return Err(format!("Key {} out of range (1-{})", s, NUM_KEYS));
+    }
+    Ok(key as u8)
+}
+
+/// The key named by `s`, where each octave of keys runs from F to E.
+fn parse_key_name(s: &str) -> Option<i32> {
+    let (letter, accidental, octave) = split_note(s)?;
+    let from_f = (letter + 7) % 12;
+    Some((octave - 1) * 12 + from_f + accidental + 1)
+}
+
+/// Parse a single key, given either as a number from 1-24 or as a key name from `F1` to `E2`,
+/// such as `C#2` or `Bb1`.
+pub fn parse_key(s: &str) -> Result<u8, String> {
+    let key = match s.parse::<i32>() {
+        Ok(key) => key,
+        Err(_) => parse_key_name(s).ok_or_else(|| format!("Invalid key: {}", s))?,
+    };
+    check_range(key, s)
+}
+
+fn parse_selector(s: &str) -> Result<Vec<u8>, String> {
+    let all = 1..=NUM_KEYS;
+    Ok(match s.to_lowercase().as_str() {
+        "all" => all.collect(),
+        "white" => all.filter(|&k| !is_black(k)).collect(),
+        "black" => all.filter(|&k| is_black(k)).collect(),
+        "octave1" => (1..=12).collect(),
+        "octave2" => (13..=24).collect(),
+        _ => {
+            let range: Vec<&str> = s.split(':').collect();
+            match range.len() {
+                1 => vec![parse_key(s)?],
+                2 | 3 => {
+                    let start = parse_key(range[0])?;
+                    let end = parse_key(range[1])?;
+                    let step = if range.len() == 3 {
+                        match range[2].parse::<usize>() {
+                            Ok(step) if step > 0 => step,
+                            _ => return Err(format!("Invalid step in key range: {}", s)),
+                        }
+                    } else {
+                        1
+                    };
+                    if start <= end {
+                        (start..=end).step_by(step).collect()
+                    } else {
+                        (end..=start).rev().step_by(step).collect()
+                    }
+                }
+                _ => return Err(format!("Invalid key: {}", s)),
+            }
+        }
+    })
+}
+
+/// Parse a list of key selectors into the keys they select, in the order given.
+///
+/// Each selector is a key, an inclusive range `a:b` with an optional step `a:b:step`, or one
+/// of `all`, `white`, `black`, `octave1` and `octave2`. Selectors prefixed with `!` exclude
+/// keys instead; if only exclusions are given, they are excluded from all keys.
+pub fn parse_keys(selectors: &[&str]) -> Result<Vec<u8>, String> {
+    let mut included: Vec<u8> = vec![];
+    let mut excluded: Vec<u8> = vec![];
+    let mut any_included = false;
+    for selector in selectors.iter() {
+        if let Some(selector) = selector.strip_prefix('!') {
+            excluded.extend(parse_selector(selector)?);
+        } else {
+            any_included = true;
+            for key in parse_selector(selector)? {
+                if !included.contains(&key) {
+                    included.push(key);
+                }
+            }
+        }
+    }
+    if !any_included {
+        included = (1..=NUM_KEYS).collect();
+    }
+    included.retain(|k| !excluded.contains(k));
+    if included.is_empty() {
+        return Err("No keys selected".to_string());
+    }
+
+    Ok(included)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_names() {
+        assert_eq!(key_name(1), "F1");
+        assert_eq!(key_name(2), "F#1");
+        assert_eq!(key_name(8), "C1");
+        assert_eq!(key_name(12), "E1");
+        assert_eq!(key_name(13), "F2");
+        assert_eq!(key_name(20), "C2");
+        assert_eq!(key_name(21), "C#2");
+        assert_eq!(key_name(24), "E2");
+    }
+
+    #[test]
+    fn note_names_round_trip() {
+        for note in 0..=127 {
+            assert_eq!(parse_note(&note_name(note)), Some(note));
+        }
+        assert_eq!(parse_note("C4"), Some(60));
+        assert_eq!(parse_note("A4"), Some(69));
+        assert_eq!(parse_note("C-1"), Some(0));
+        assert_eq!(parse_note("Cb-1"), None);
+        assert_eq!(parse_note("G#9"), None);
+        assert_eq!(parse_note("H4"), None);
+    }
+
+    #[test]
+    fn parse_key_numbers_and_notes() {
+        assert_eq!(parse_key("1"), Ok(1));
+        assert_eq!(parse_key("24"), Ok(24));
+        assert_eq!(parse_key("F1"), Ok(1));
+        assert_eq!(parse_key("C#2"), Ok(21));
+        assert_eq!(parse_key("c#2"), Ok(21));
+        assert_eq!(parse_key("E1"), Ok(12));
+        assert_eq!(parse_key("F2"), Ok(13));
+        assert_eq!(parse_key("E2"), Ok(24));
+    }
+
+    #[test]
+    fn key_names_round_trip() {
+        for key in 1..=NUM_KEYS {
+            assert_eq!(parse_key(&key_name(key)), Ok(key));
+        }
+    }
+
+    #[test]
+    fn parse_key_flats() {
+        assert_eq!(parse_key("Bb1"), Ok(6));
+        assert_eq!(parse_key("Bb1"), parse_key("A#1"));
+        assert_eq!(parse_key("Gb2"), Ok(14));
+        assert_eq!(parse_key("Db2"), parse_key("C#2"));
+    }
+
+    #[test]
+    fn parse_key_out_of_range() {
+        assert!(parse_key("0").is_err());
+        assert!(parse_key("25").is_err());
+        assert!(parse_key("Fb1").is_err());
+        assert!(parse_key("E0").is_err());
+        assert!(parse_key("F3").is_err());
+        assert!(parse_key("E#2").is_err());
+        assert!(parse_key("H1").is_err());
+    }
+
+    #[test]
+    fn selector_ranges() {
+        assert_eq!(parse_selector("1:4"), Ok(vec![1, 2, 3, 4]));
+        assert_eq!(parse_selector("F1:G#1"), Ok(vec![1, 2, 3, 4]));
+        assert_eq!(parse_selector("C2:E2"), Ok(vec![20, 21, 22, 23, 24]));
+        assert_eq!(parse_selector("4:1"), Ok(vec![4, 3, 2, 1]));
+        assert_eq!(parse_selector("3:3"), Ok(vec![3]));
+    }
+
+    #[test]
+    fn selector_steps() {
+        assert_eq!(parse_selector("1:7:3"), Ok(vec![1, 4, 7]));
+        assert_eq!(parse_selector("1:8:3"), Ok(vec![1, 4, 7]));
+        assert_eq!(parse_selector("7:1:3"), Ok(vec![7, 4, 1]));
+        assert!(parse_selector("1:7:0").is_err());
+        assert!(parse_selector("1:7:x").is_err());
+        assert!(parse_selector("1:2:3:4").is_err());
+    }
+
+    #[test]
+    fn selector_groups() {
+        assert_eq!(parse_selector("all"), Ok((1..=24).collect()));
+        assert_eq!(parse_selector("white").unwrap().len(), 14);
+        assert_eq!(
+            parse_selector("black"),
+            Ok(vec![2, 4, 6, 9, 11, 14, 16, 18, 21, 23])
+        );
+        assert_eq!(parse_selector("Octave2"), Ok((13..=24).collect()));
+    }
+
+    #[test]
+    fn parse_keys_keeps_order_without_duplicates() {
+        assert_eq!(parse_keys(&["3", "1", "3"]), Ok(vec![3, 1]));
+        assert_eq!(parse_keys(&["2:1", "1:3"]), Ok(vec![2, 1, 3]));
+        assert_eq!(parse_keys(&["C#2", "Bb1"]), Ok(vec![21, 6]));
+    }
+
+    #[test]
+    fn parse_keys_exclusions() {
+        let keys = parse_keys(&["all", "!13"]).unwrap();
+        assert_eq!(keys.len(), 23);
+        assert!(!keys.contains(&13));
+        assert_eq!(parse_keys(&["!octave1"]), Ok((13..=24).collect()));
+        assert_eq!(parse_keys(&["!1", "1:3"]), Ok(vec![2, 3]));
+        assert_eq!(parse_keys(&["1:6", "!black"]), Ok(vec![1, 3, 5]));
+        assert!(parse_keys(&["1", "!1"]).is_err());
+        assert!(parse_keys(&["!all"]).is_err());
+    }
+}
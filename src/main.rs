@@ -1,12 +1,27 @@
+mod audio;
+mod batch;
 mod chunks;
+mod diff;
+mod info;
+mod inspect;
+mod json_edit;
+mod keys;
+mod manifest;
 mod op1;
+mod parse;
+mod patch;
+mod repair;
 mod util;
+mod validate;
+mod wav_meta;
 
 use chunks::{read_aif, ApplicationSpecificChunk, Chunk};
-use clap::{value_t_or_exit, values_t_or_exit, App, Arg, ArgMatches, SubCommand};
+use clap::{value_t_or_exit, App, Arg, ArgMatches, SubCommand};
+use serde_json::Value;
 use std::error;
 use std::fs::{self, File};
-use std::io::{self, StdinLock, StdoutLock};
+use std::io::{self, Read, StdinLock, StdoutLock, Write};
+use std::path::{Path, PathBuf};
 
 fn main() -> Result<(), Box<dyn error::Error>> {
     let version = env!("CARGO_PKG_VERSION");
@@ -27,120 +42,163 @@ fn main() -> Result<(), Box<dyn error::Error>> {
                 .short("q")
                 .help("Silence all output."),
         )
+        .subcommands(edit_commands())
         .subcommand(
-            io_command(key_command(
-                SubCommand::with_name("silence"),
-                "KEYS",
-                "keys",
-            ))
-                .about("Turn sample gain to -inf"),
-        )
-        .subcommand(
-            io_command(key_command(
-                SubCommand::with_name("volume"),
-                "KEYS",
-                "keys",
-            ))
-                .arg(Arg::with_name("VOLUME")
-                     .short("g")
-                     .long("gain")
-                     .value_name("VOLUME")
-                     .use_delimiter(true)
-                     .required(true)
-                     .help("A list of comma-separated numbers between -1-+1, representing the amount of gain to apply. If more keys are provided than gain values, the last gain will be applied to any remaining keys."))
-                .about("Set sample gain to a value between -1.0 (-inf) and +1.0 (+12 dB)"),
+            io_command_with_default(SubCommand::with_name("dump"), "op.json")
+                .arg(Arg::with_name("VOLUME_DB")
+                     .long("volume-db")
+                     .help("Add a `volume_db` field with the volume of each key in dB. This field is ignored by `set`."))
+                .about("Output the OP metadata associated with a patch"),
         )
         .subcommand(
-            io_command(key_command(
-                SubCommand::with_name("reverse"),
-                "KEYS",
-                "keys",
-            ))
-                .about("Set sample to play in reverse"),
+            SubCommand::with_name("diff")
+                .arg(Arg::with_name("A").index(1).required(true))
+                .arg(Arg::with_name("B").index(2).required(true))
+                .about("Compare the metadata and audio of two patches")
+                .after_help("Exits with 0 if the patches are identical, 1 if only their metadata differs, 2 if their audio differs, and 3 on error."),
         )
         .subcommand(
-            io_command(key_command(
-                SubCommand::with_name("forward"),
-                "KEYS",
-                "keys",
-            ))
-                .about("Set sample to play forward"),
+            SubCommand::with_name("validate")
+                .arg(Arg::with_name("INPUT").index(1).help("Omit to use STDIN."))
+                .arg(check_device_arg())
+                .about("Check that a patch will load on the OP-1 or OP-Z"),
         )
         .subcommand(
-            io_command(key_command(
-                SubCommand::with_name("copy"),
-                "KEYS",
-                "keys",
-            ))
-                .arg(Arg::with_name("SRC")
-                     .short("s")
-                     .long("src")
-                     .value_name("SRC")
-                     .use_delimiter(true)
-                     .required(true)
-                     .help("Same as KEYS, but this is the key that is being copied. If there are more KEYS that SRCs, then the last SRC will be copied to all remaining destinations."))
-                .about("Copy samples from one set of keys to another"),
+            edit_io_command(SubCommand::with_name("repair"))
+                .about("Recover what can be recovered from a damaged patch"),
         )
         .subcommand(
-            io_command(key_command(
-                SubCommand::with_name("pitch"),
-                "KEYS",
-                "keys",
-            ))
-                .arg(Arg::with_name("PITCH")
-                     .short("p")
-                     .long("pitch")
-                     .value_name("PITCH")
-                     .use_delimiter(true)
-                     .required(true)
-                     .help("A list of comma-separated numbers between -48-+48, representing the number of semitones to shift pitch by. Colons can be used to represent inclusive ranges for whole semitones. Only whole numbers may be used. If more keys are provided than pitch values, the last pitch will be applied to any remaining keys. E.g.: `-k 1:7 -p -7:-1` will shift the lower F to B keys by -7 to -1 semitones; `-k 1-24 -p 2` will shift all keys up by 2 semitones."))
-                .about("Shift the pitch of a given key"),
+            SubCommand::with_name("inspect")
+                .arg(Arg::with_name("INPUT").index(1).help("Omit to use STDIN."))
+                .arg(Arg::with_name("JSON")
+                     .long("json")
+                     .help("Output JSON instead of text."))
+                .about("List the chunks of a patch, for debugging"),
         )
         .subcommand(
-            io_command_with_default(SubCommand::with_name("dump"), "op.json")
-                .about("Output the OP metadata associated with a patch"),
+            SubCommand::with_name("info")
+                .arg(Arg::with_name("INPUT").index(1).help("Omit to use STDIN."))
+                .arg(check_device_arg())
+                .about("Summarize the contents of a patch"),
         )
         .subcommand(
-            io_command(SubCommand::with_name("set"))
+            edit_io_command(SubCommand::with_name("set"))
                 .arg(
                     Arg::with_name("JSON")
                         .value_name("JSON")
                         .short("j")
                         .long("json")
-                        .required(true)
-                        .help("The JSON file with which to overwrite the OP metadata. Must be valid OP metadata.")
+                        .required_unless("FIELD")
+                        .help("A JSON file with which to edit the OP metadata. An object is merged into the metadata: its fields replace those of the metadata, and fields set to `null` are removed. An object with a different `type` replaces the metadata. An array is applied as a JSON Patch (RFC 6902).")
+                )
+                .arg(
+                    Arg::with_name("FIELD")
+                        .value_name("POINTER=VALUE")
+                        .short("f")
+                        .long("field")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Set the field at a JSON Pointer to a value, after applying JSON. Values that aren't valid JSON are taken as strings. E.g. `--field /fx_params/3=12000`.")
                 )
-                .about("Overwrite the OP metadata with a given JSON file"),
+                .about("Edit the OP metadata with a JSON file or field assignments"),
+        )
+        .subcommand(
+            edit_io_command(SubCommand::with_name("edit"))
+                .about("Apply a sequence of edits to a patch, writing it once")
+                .arg(Arg::with_name("STEPS")
+                     .index(3)
+                     .multiple(true)
+                     .last(true)
+                     .required_unless("SCRIPT")
+                     .help("The edits to make, separated by `/`, after `--`. Each is the name of an editing subcommand followed by its options. The keys and value of key edits, and the N of `shift`, can be given without their flags: `copy` takes SRC then KEYS. E.g. `-- shift 7 / pitch 1:5 -2 / volume all -3dB -r`."))
+                .arg(Arg::with_name("SCRIPT")
+                     .long("script")
+                     .value_name("FILE")
+                     .conflicts_with("STEPS")
+                     .help("Read the edits from a file, one per line, instead of STEPS. Blank lines and lines starting with `#` are ignored."))
         )
         .subcommand(
-            io_command(SubCommand::with_name("shift"))
-                .about("Shift the samples up or down by N keys")
-                .arg(
-                    Arg::with_name("N")
-                        .value_name("N")
-                        .short("n")
-                        .required(true),
-                ),
-        ).subcommand(
             io_command(SubCommand::with_name("synth"))
-                .about("Create a synth sampler from a WAV file")
+                .about("Create a synth sampler from a WAV or AIFF file")
                 .arg(
                     Arg::with_name("BASE_FREQ")
                         .value_name("BASE_FREQ")
                         .short("f")
                         .default_value("440")
-                ),
+                )
+                .arg(
+                    Arg::with_name("DETECT_PITCH")
+                        .long("detect-pitch")
+                        .help("Detect BASE_FREQ from the pitch of the sample. If no pitch can be found, a note name at the end of the input filename is used instead (e.g. `pad_C3.wav`).")
+                )
+                .arg(
+                    Arg::with_name("LOOP")
+                        .long("loop")
+                        .value_name("MODE")
+                        .possible_values(&["auto"])
+                        .help("Set the sampler's loop points. With `auto`, the loop stored in the source is used if there is one (a WAV `smpl` chunk or AIFF sustain loop). Otherwise a loop is found at zero crossings where the waveform matches, and a crossfade is baked into the end of the loop.")
+                )
+                .arg(
+                    Arg::with_name("MULTISAMPLE")
+                        .long("multisample")
+                        .value_name("DIR")
+                        .conflicts_with("INPUT")
+                        .help("Use a directory of WAV or AIFF files, one per note, instead of INPUT. The note of each file is taken from its metadata or filename (e.g. `piano_C4.wav`), or else detected. The file closest to the middle of RANGE is used as the source.")
+                )
+                .arg(
+                    Arg::with_name("RANGE")
+                        .long("range")
+                        .value_name("LOW:HIGH")
+                        .requires("MULTISAMPLE")
+                        .help("The range of notes the multisampled patch will be played over. Notes are named in scientific pitch notation, so that middle C is C4. Defaults to F3:E5, the range of the keyboard.")
+                )
+                .arg(
+                    Arg::with_name("DRUM")
+                        .long("drum")
+                        .requires("MULTISAMPLE")
+                        .help("Create a drum patch instead, mapping every key to the closest note file and pitching it to the note of the key.")
+                )
+                .arg(name_arg())
+                .arg(device_arg()),
+        ).subcommand(
+            SubCommand::with_name("batch")
+                .about("Build a patch for every directory of samples or kit manifest in a directory tree")
+                .arg(Arg::with_name("INPUT_DIR")
+                     .index(1)
+                     .required(true)
+                     .help("The directory to search. Every directory with kit manifests (`*.toml`) produces one patch per manifest, and every other directory with WAV or AIFF files produces one patch."))
+                .arg(Arg::with_name("OUTPUT_DIR")
+                     .index(2)
+                     .required(true)
+                     .help("Where to write the patches, at the same relative paths as their sources."))
+                .arg(Arg::with_name("SYNTH")
+                     .long("synth")
+                     .help("Build synth patches from directories of samples instead of drum patches. Each directory is treated as a multisample, as with `synth --multisample`."))
+                .arg(Arg::with_name("JOBS")
+                     .short("j")
+                     .long("jobs")
+                     .value_name("N")
+                     .help("How many patches to build at once. Defaults to the number of CPUs."))
+                .arg(device_arg()),
         ).subcommand(
             SubCommand::with_name("drum")
                 .arg(Arg::with_name("INPUT_FILES")
                      .index(1)
                      .max_values(24)
-                     .required(true)
+                     .required_unless("MANIFEST")
                      .multiple(true)
-                     .help("Up to 24 WAV files which will map to the 24 keys of the OP. The ordering of the inputs will be based on their name. Any keys that do not have a sample will be silent by default. Total length of the samples may not exceed 12 seconds."))
+                     .help("Up to 24 WAV or AIFF files which will map to the 24 keys of the OP. The ordering of the inputs will be based on their name. Any keys that do not have a sample will be silent by default. Total length of the samples may not exceed 12 seconds."))
                 .arg(Arg::with_name("USE_INPUT_ORDERING")
                      .long("use-input-ordering")
                      .help("Instead of using the input filenames to determine sample ordering, use the order of the files as passed in the command line."))
+                .arg(Arg::with_name("MANIFEST")
+                     .long("manifest")
+                     .value_name("FILE")
+                     .conflicts_with_all(&["INPUT_FILES", "USE_INPUT_ORDERING", "SHIFT", "COPY_REMAINING", "PITCH_SHIFT_REMAINING", "LOW_RES", "USE_ROOT_NOTES", "OCTAVE"])
+                     .help("Build the patch described by a TOML kit manifest, instead of from INPUT_FILES. See the readme for the format."))
+                .arg(Arg::with_name("USE_ROOT_NOTES")
+                     .long("use-root-notes")
+                     .help("Pitch each sample so that the root note stored in its file (in the `smpl` or `inst` chunk of a WAV file, or the INST chunk of an AIFF file) plays on the key it lands on."))
                 .arg(Arg::with_name("OCTAVE")
                      .short("t")
                      .long("octave")
@@ -170,7 +228,9 @@ fn main() -> Result<(), Box<dyn error::Error>> {
                      .short("l")
                      .long("low-res")
                      .help("Halve the sample rate, but pitch up the result by an octave. This effectively doubles the total available sample length to 24 seconds, at the expense of a lower-resolution."))
-                .about("Create a drum patch from up to 24 WAV files")
+                .arg(name_arg())
+                .arg(device_arg())
+                .about("Create a drum patch from up to 24 WAV or AIFF files")
         );
 
     let mut help = vec![];
@@ -189,17 +249,35 @@ fn main() -> Result<(), Box<dyn error::Error>> {
         .unwrap();
 
     match matches.subcommand() {
-        ("shift", Some(sub_m)) => shift(sub_m)?,
-        ("silence", Some(sub_m)) => silence(sub_m)?,
-        ("pitch", Some(sub_m)) => pitch(sub_m)?,
-        ("volume", Some(sub_m)) => volume(sub_m)?,
-        ("reverse", Some(sub_m)) => reverse(sub_m)?,
-        ("forward", Some(sub_m)) => forward(sub_m)?,
-        ("copy", Some(sub_m)) => copy(sub_m)?,
+        ("shift", Some(sub_m)) => op(sub_m, shift(sub_m)?)?,
+        ("envelope", Some(sub_m)) => op(sub_m, envelope(sub_m)?)?,
+        ("knobs", Some(sub_m)) => op(sub_m, knobs(sub_m)?)?,
+        ("fx", Some(sub_m)) => op(sub_m, fx(sub_m)?)?,
+        ("lfo", Some(sub_m)) => op(sub_m, lfo(sub_m)?)?,
+        ("silence", Some(sub_m)) => op(sub_m, silence(sub_m)?)?,
+        ("pitch", Some(sub_m)) => op(sub_m, pitch(sub_m)?)?,
+        ("volume", Some(sub_m)) => op(sub_m, volume(sub_m)?)?,
+        ("reverse", Some(sub_m)) => op(sub_m, reverse(sub_m)?)?,
+        ("forward", Some(sub_m)) => op(sub_m, forward(sub_m)?)?,
+        ("playmode", Some(sub_m)) => op(sub_m, playmode(sub_m)?)?,
+        ("copy", Some(sub_m)) => op(sub_m, copy(sub_m)?)?,
         ("dump", Some(sub_m)) => dump(sub_m)?,
+        ("info", Some(sub_m)) => info(sub_m)?,
+        ("inspect", Some(sub_m)) => inspect(sub_m)?,
+        ("validate", Some(sub_m)) => validate(sub_m)?,
+        ("repair", Some(sub_m)) => repair(sub_m)?,
+        ("diff", Some(sub_m)) => match diff(sub_m) {
+            Ok(code) => std::process::exit(code),
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(3);
+            }
+        },
         ("set", Some(sub_m)) => set(sub_m)?,
+        ("edit", Some(sub_m)) => edit(sub_m)?,
         ("synth", Some(sub_m)) => synth(sub_m)?,
         ("drum", Some(sub_m)) => drum(sub_m)?,
+        ("batch", Some(sub_m)) => batch(sub_m)?,
         _ => {
             eprintln!("Error: subcommand required\n");
             println!("{}", help);
@@ -209,10 +287,151 @@ fn main() -> Result<(), Box<dyn error::Error>> {
     Ok(())
 }
 
+/// The subcommands that edit the OP metadata of a patch, which can also be chained with `edit`.
+fn edit_commands<'a, 'b>() -> Vec<App<'a, 'b>> {
+    vec![
+        edit_io_command(key_command(
+            SubCommand::with_name("silence"),
+            "KEYS",
+            "keys",
+        ))
+            .about("Turn sample gain to -inf"),
+        edit_io_command(key_command(
+            SubCommand::with_name("volume"),
+            "KEYS",
+            "keys",
+        ))
+            .arg(Arg::with_name("VOLUME")
+                 .short("g")
+                 .long("gain")
+                 .value_name("VOLUME")
+                 .use_delimiter(true)
+                 .allow_hyphen_values(true)
+                 .required(true)
+                 .help("A list of comma-separated volumes. Volumes are in decibels when suffixed with `dB` (e.g. `+3dB`, `-inf`), from -inf to +12 dB. Otherwise they are numbers between -1-+1, representing the amount of gain to apply, where 0 is unity gain. If more keys are provided than gain values, the last gain will be applied to any remaining keys."))
+            .arg(Arg::with_name("RAW")
+                 .long("raw")
+                 .help("Interpret volumes without a `dB` suffix as the raw values stored in the patch, from 0 (-inf) to 16384 (+12 dB)."))
+            .arg(Arg::with_name("RELATIVE")
+                 .short("r")
+                 .long("relative")
+                 .help("Add the volumes to the current volume of each key, rather than replacing it."))
+            .about("Set sample gain to a value between -inf and +12 dB"),
+        edit_io_command(key_command(
+            SubCommand::with_name("reverse"),
+            "KEYS",
+            "keys",
+        ))
+            .about("Set sample to play in reverse"),
+        edit_io_command(key_command(
+            SubCommand::with_name("forward"),
+            "KEYS",
+            "keys",
+        ))
+            .about("Set sample to play forward"),
+        edit_io_command(key_command(
+            SubCommand::with_name("playmode"),
+            "KEYS",
+            "keys",
+        ))
+            .arg(Arg::with_name("MODE")
+                 .short("m")
+                 .long("mode")
+                 .value_name("MODE")
+                 .possible_values(&["gate", "oneshot", "loop"])
+                 .required(true)
+                 .help("`gate` plays the sample while the key is held, `oneshot` plays the whole sample, and `loop` loops the sample while the key is held."))
+            .about("Set how samples are played"),
+        edit_io_command(key_command(
+            SubCommand::with_name("copy"),
+            "KEYS",
+            "keys",
+        ))
+            .arg(Arg::with_name("SRC")
+                 .short("s")
+                 .long("src")
+                 .value_name("SRC")
+                 .use_delimiter(true)
+                 .required(true)
+                 .help("Same as KEYS, but this is the key that is being copied. If there are more KEYS that SRCs, then the last SRC will be copied to all remaining destinations."))
+            .about("Copy samples from one set of keys to another"),
+        edit_io_command(key_command(
+            SubCommand::with_name("pitch"),
+            "KEYS",
+            "keys",
+        ))
+            .arg(Arg::with_name("PITCH")
+                 .short("p")
+                 .long("pitch")
+                 .value_name("PITCH")
+                 .use_delimiter(true)
+                 .allow_hyphen_values(true)
+                 .required(true)
+                 .help("A list of comma-separated pitches between -48-+48 semitones. Pitches are in semitones, optionally suffixed with `st` (e.g. `+12.5st`), or in cents when suffixed with `c` (e.g. `-35c`). Colons can be used to represent ranges, which step by a semitone from the first pitch towards the second (e.g. `-50c:150c` is -0.5, 0.5 and 1.5 semitones). Pitches beyond -48-+48 semitones are clamped. If more keys are provided than pitch values, the last pitch will be applied to any remaining keys. E.g.: `-k 1:7 -p -7:-1` will shift the lower F to B keys by -7 to -1 semitones; `-k all -p 2` will shift all keys up by 2 semitones."))
+            .arg(Arg::with_name("RELATIVE")
+                 .short("r")
+                 .long("relative")
+                 .help("Add the pitches to the current pitch of each key, rather than replacing it."))
+            .about("Shift the pitch of a given key"),
+        fx_command(SubCommand::with_name("fx"), FX_TYPES)
+            .about("Set the effect of a patch"),
+        fx_command(SubCommand::with_name("lfo"), LFO_TYPES)
+            .about("Set the LFO of a patch"),
+        edit_io_command(SubCommand::with_name("envelope"))
+            .arg(envelope_arg("ATTACK", "attack", "TIME")
+                 .help("The attack time in `ms` or `s` (e.g. `120ms`, `1.5s`), up to 10 seconds."))
+            .arg(envelope_arg("DECAY", "decay", "TIME")
+                 .help("The decay time in `ms` or `s`, up to 10 seconds."))
+            .arg(envelope_arg("SUSTAIN", "sustain", "LEVEL")
+                 .help("The sustain level, between 0-1."))
+            .arg(envelope_arg("RELEASE", "release", "TIME")
+                 .help("The release time in `ms` or `s`, up to 10 seconds."))
+            .about("Set the envelope of a synth patch"),
+        edit_io_command(SubCommand::with_name("knobs"))
+            .arg(Arg::with_name("PARAM")
+                 .short("p")
+                 .long("param")
+                 .value_name("NAME=VALUE")
+                 .use_delimiter(true)
+                 .required(true)
+                 .help("A list of comma-separated knobs to set, as a name and a value between 0-1. The knobs are `start`, `loop_start`, `loop_end`, `end` and `hold`. E.g. `-p loop_start=0.2,loop_end=0.8`."))
+            .about("Set the knobs of a sampler patch"),
+        edit_io_command(SubCommand::with_name("shift"))
+            .about("Shift the samples up or down by N keys")
+            .arg(
+                Arg::with_name("N")
+                    .value_name("N")
+                    .short("n")
+                    .allow_hyphen_values(true)
+                    .required(true),
+            ),
+    ]
+}
+
 fn io_command<'a, 'b>(command: App<'a, 'b>) -> App<'a, 'b> {
     io_command_with_default(command, "output.aif")
 }
 
+/// An `io_command` for a subcommand that edits a patch, which can also edit it in place.
+fn edit_io_command<'a, 'b>(command: App<'a, 'b>) -> App<'a, 'b> {
+    io_command(command)
+        .arg(device_arg())
+        .arg(
+            Arg::with_name("IN_PLACE")
+                .short("i")
+                .long("in-place")
+                .requires("INPUT")
+                .conflicts_with_all(&["OUTPUT", "OUTPUT_FILE"])
+                .help("Overwrite INPUT instead of writing a new file. INPUT is only replaced once the edited patch has been written in full."),
+        )
+        .arg(
+            Arg::with_name("BACKUP")
+                .long("backup")
+                .requires("IN_PLACE")
+                .help("Keep a copy of INPUT with `.bak` appended."),
+        )
+}
+
 fn io_command_with_default<'a, 'b>(command: App<'a, 'b>, default: &'static str) -> App<'a, 'b> {
     command
         .arg(Arg::with_name("INPUT").index(1).help("Omit to use STDIN."))
@@ -241,7 +460,67 @@ fn key_command<'a, 'b>(
              .value_name(name)
              .use_delimiter(true)
              .required(true)
-             .help("One or more comma-separated numbers between 1-24, representing the keys on the OP that are to be modified. Colons can be used to represent inclusive ranges. E.g.: `1,2,13,14` is both F and F# keys; `1:7,13:19` is both sets of F to B keys."))
+             .help("One or more comma-separated keys on the OP that are to be modified. Keys are either numbers between 1-24 or note names from F1-E1 and F2-E2 (e.g. `C#2`, `Bb1`). Colons can be used to represent inclusive ranges, with an optional step. `all`, `white`, `black`, `octave1` and `octave2` select groups of keys, and a leading `!` excludes keys. E.g.: `1,2,13,14` is both F and F# keys; `1:7,13:19` is both sets of F to B keys; `F1:E2:2` is every other key; `all,!13` is every key but F2."))
+}
+
+fn envelope_arg<'a, 'b>(name: &'a str, long: &'a str, value_name: &'a str) -> Arg<'a, 'b> {
+    Arg::with_name(name)
+        .short(&long[0..1])
+        .long(long)
+        .value_name(value_name)
+}
+
+fn name_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("NAME")
+        .long("name")
+        .value_name("NAME")
+        .default_value("user")
+        .help("The name of the patch.")
+}
+
+fn device_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("DEVICE")
+        .long("device")
+        .value_name("DEVICE")
+        .possible_values(&["op1", "opz"])
+        .help("The device to create patches for, which determines their limits and metadata. Defaults to op1 for new patches. When editing, giving a device converts the patch to it; otherwise the metadata is left as it is.")
+}
+
+/// `--device` for commands that read a patch without changing it. Patches don't record the
+/// device they were made for, so it has to be given.
+fn check_device_arg<'a, 'b>() -> Arg<'a, 'b> {
+    device_arg().help("The device whose limits the patch is checked against. Defaults to op1.")
+}
+
+fn matches_device(matches: &ArgMatches) -> Option<op1::Device> {
+    matches
+        .value_of("DEVICE")
+        .map(|device| device.parse().unwrap())
+}
+
+const FX_TYPES: &[&str] = &["delay", "spring", "punch", "grid", "nitro", "phone", "cwo"];
+const LFO_TYPES: &[&str] = &[
+    "tremolo", "bend", "crank", "element", "midi", "random", "value",
+];
+
+fn fx_command<'a, 'b>(command: App<'a, 'b>, types: &'static [&'static str]) -> App<'a, 'b> {
+    edit_io_command(command)
+        .arg(Arg::with_name("TYPE")
+             .short("t")
+             .long("type")
+             .value_name("TYPE")
+             .possible_values(types)
+             .help("The type to switch to. Switching type resets the parameters to their defaults before PARAM is applied. Omit to keep the current type."))
+        .arg(Arg::with_name("PARAM")
+             .short("p")
+             .long("param")
+             .value_name("NAME=VALUE")
+             .use_delimiter(true)
+             .help("A list of comma-separated parameters to set, as a name and a value between 0-1. E.g. `-p mix=0.4,rate=1`. The names available depend on the type."))
+        .arg(Arg::with_name("OFF")
+             .long("off")
+             .conflicts_with_all(&["TYPE", "PARAM"])
+             .help("Turn off instead of on."))
 }
 
 enum Input<'a> {
@@ -249,7 +528,12 @@ enum Input<'a> {
     Stdin(StdinLock<'a>),
 }
 enum Output<'a> {
-    File(File),
+    /// Written once the output is complete, keeping any existing file at `path` as a backup
+    /// when `backup` is set.
+    File {
+        path: PathBuf,
+        backup: bool,
+    },
     Stdout(StdoutLock<'a>),
 }
 
@@ -257,6 +541,17 @@ fn matches_io<'a>(matches: &ArgMatches) -> Result<(Input<'a>, Output<'a>), Box<d
     let stdin = Box::leak(Box::new(io::stdin()));
     let stdout = Box::leak(Box::new(io::stdout()));
 
+    if matches.is_present("IN_PLACE") {
+        let input = matches.value_of("INPUT").unwrap();
+        return Ok((
+            Input::File(File::open(input)?),
+            Output::File {
+                path: input.into(),
+                backup: matches.is_present("BACKUP"),
+            },
+        ));
+    }
+
     Ok(
         match (
             matches.value_of("INPUT"),
@@ -268,7 +563,10 @@ fn matches_io<'a>(matches: &ArgMatches) -> Result<(Input<'a>, Output<'a>), Box<d
             }
             (Some("-"), None, Some(output)) | (Some("-"), Some(output), _) => (
                 Input::Stdin(stdin.lock()),
-                Output::File(File::create(output)?),
+                Output::File {
+                    path: output.into(),
+                    backup: false,
+                },
             ),
             (Some(input), Some("-"), _) => (
                 Input::File(File::open(input)?),
@@ -276,74 +574,118 @@ fn matches_io<'a>(matches: &ArgMatches) -> Result<(Input<'a>, Output<'a>), Box<d
             ),
             (Some(input), Some(output), _) => (
                 Input::File(File::open(input)?),
-                Output::File(File::create(output)?),
+                Output::File {
+                    path: output.into(),
+                    backup: false,
+                },
             ),
             (None, Some(output), _) => (
                 Input::Stdin(stdin.lock()),
-                Output::File(File::create(output)?),
+                Output::File {
+                    path: output.into(),
+                    backup: false,
+                },
             ),
             (Some(input), None, Some(output)) => (
                 Input::File(File::open(input)?),
-                Output::File(File::create(output)?),
+                Output::File {
+                    path: output.into(),
+                    backup: false,
+                },
             ),
             (None, None, Some(output)) => (
                 Input::Stdin(stdin.lock()),
-                Output::File(File::create(output)?),
+                Output::File {
+                    path: output.into(),
+                    backup: false,
+                },
             ),
             _ => panic!("This should not be possible"),
         },
     )
 }
 
-fn matches_keys<'a>(matches: &ArgMatches, key_arg: &str) -> Result<Vec<u8>, Box<dyn error::Error>> {
+/// Write a file through a temporary file in the same directory, which is renamed over `path`
+/// once `f` has succeeded. With `backup`, any existing file at `path` is first copied to
+/// `path` with `.bak` appended.
+fn write_file<F, T>(path: &Path, backup: bool, f: F) -> Result<T, Box<dyn error::Error>>
+where
+    F: FnOnce(&mut File) -> Result<T, io::Error>,
+{
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = path.file_name().ok_or("Invalid output path")?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = dir.join(temp_name);
+
+    let result = File::create(&temp_path).and_then(|mut file| {
+        let written = f(&mut file)?;
+        file.sync_all()?;
+        Ok(written)
+    });
+    let written = result.map_err(|e| {
+        let _ = fs::remove_file(&temp_path);
+        format!("Could not write {}: {}", path.display(), e)
+    })?;
+
+    if backup && path.exists() {
+        let mut backup_path = path.as_os_str().to_os_string();
+        backup_path.push(".bak");
+        fs::copy(path, &backup_path)?;
+    }
+    fs::rename(&temp_path, path).map_err(|e| {
+        let _ = fs::remove_file(&temp_path);
+        format!("Could not write {}: {}", path.display(), e)
+    })?;
+    Ok(written)
+}
+
+fn matches_keys(matches: &ArgMatches, key_arg: &str) -> Result<Vec<u8>, Box<dyn error::Error>> {
     let keys: Vec<&str> = matches.values_of(key_arg).unwrap().collect();
+    let keys = keys::parse_keys(&keys)?;
+    log::info!(
+        "{}: {}",
+        key_arg,
+        keys.iter()
+            .map(|&k| keys::key_name(k))
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    Ok(keys)
+}
+
+fn matches_pitches(
+    matches: &ArgMatches,
+    pitch_arg: &str,
+) -> Result<Vec<f32>, Box<dyn error::Error>> {
     let mut r = vec![];
-    for key in keys.iter() {
-        let range: Vec<&str> = key.split(":").collect();
-        match range.len() {
-            1 => r.push(key.parse::<u8>()?),
-            2 => {
-                let start = range[0].parse::<u8>()?;
-                let end = range[1].parse::<u8>()?;
-                if start < end {
-                    r.extend((start..=end).collect::<Vec<u8>>());
-                } else {
-                    r.extend((end..=start).rev().collect::<Vec<u8>>());
-                }
-            }
-            _ => Err(format!("Invalid key: {}", key))?,
-        }
+    for pitch in matches.values_of(pitch_arg).unwrap() {
+        r.extend(parse::parse_pitches(pitch)?);
     }
 
     Ok(r)
 }
 
-fn matches_pitches<'a>(
+fn matches_volumes(
     matches: &ArgMatches,
-    pitch_arg: &str,
-) -> Result<Vec<i8>, Box<dyn error::Error>> {
-    let pitches: Vec<&str> = matches.values_of(pitch_arg).unwrap().collect();
+    volume_arg: &str,
+) -> Result<Vec<op1::Volume>, Box<dyn error::Error>> {
+    let raw = matches.is_present("RAW");
     let mut r = vec![];
-    for pitch in pitches.iter() {
-        let range: Vec<&str> = pitch.split(":").collect();
-        match range.len() {
-            1 => r.push(pitch.parse::<i8>()?),
-            2 => {
-                let start = range[0].parse::<i8>()?;
-                let end = range[1].parse::<i8>()?;
-                if start < end {
-                    r.extend((start..=end).collect::<Vec<i8>>());
-                } else {
-                    r.extend((end..=start).rev().collect::<Vec<i8>>());
-                }
-            }
-            _ => Err(format!("Invalid pitch: {}", pitch))?,
-        }
+    for volume in matches.values_of(volume_arg).unwrap() {
+        r.push(parse::parse_volume(volume, raw)?);
     }
 
     Ok(r)
 }
 
+/// A change to the OP metadata of a patch, made by one of the editing subcommands.
+type Edit = Box<dyn Fn(&mut op1::OP1Data) -> Result<(), String>>;
+
 fn op<F>(matches: &ArgMatches, f: F) -> Result<(), Box<dyn error::Error>>
 where
     F: Fn(&mut op1::OP1Data) -> Result<(), String>,
@@ -356,55 +698,261 @@ where
 
     log::info!("Input file: {:#?}", &form);
 
-    if let Some(ApplicationSpecificChunk::OP1 { data }) = form.app.first_mut() {
+    if let Some(ApplicationSpecificChunk::OP1 { data, .. }) = form.app.first_mut() {
         f(data)?;
+        if let Some(device) = matches_device(matches) {
+            log::info!("Device: {}", device);
+            data.set_device(device)?;
+        }
     } else {
         Err("No OP data to alter")?;
     }
 
     match o {
         Output::Stdout(mut stdout) => form.write(&mut stdout)?,
-        Output::File(mut file) => form.write(&mut file)?,
+        Output::File { path, backup } => write_file(&path, backup, |file| form.write(file))?,
     };
     Ok(())
 }
 
-fn shift(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
+fn matches_params(
+    matches: &ArgMatches,
+    param_arg: &str,
+) -> Result<Vec<(String, f32)>, Box<dyn error::Error>> {
+    let mut r = vec![];
+    for param in matches.values_of(param_arg).into_iter().flatten() {
+        match param.split_once('=') {
+            Some((name, value)) => r.push((
+                name.to_string(),
+                value
+                    .parse::<f32>()
+                    .map_err(|_| format!("Invalid parameter: {}", param))?,
+            )),
+            None => Err(format!("Invalid parameter: {}", param))?,
+        }
+    }
+
+    Ok(r)
+}
+
+fn envelope(matches: &ArgMatches) -> Result<Edit, Box<dyn error::Error>> {
+    let mut stages = vec![];
+    for (arg, name) in [
+        ("ATTACK", "attack"),
+        ("DECAY", "decay"),
+        ("RELEASE", "release"),
+    ] {
+        if let Some(time) = matches.value_of(arg) {
+            stages.push((name, op1::ms_to_envelope(parse::parse_time(time)?)?));
+        }
+    }
+    if let Some(level) = matches.value_of("SUSTAIN") {
+        let level = level
+            .parse::<f32>()
+            .map_err(|_| format!("Invalid sustain level: {}", level))?;
+        if !(0.0..=1.0).contains(&level) {
+            Err(format!("Sustain level {} out of range (0-1)", level))?;
+        }
+        stages.push(("sustain", (level * op1::MAX_PARAM as f32).round() as u16));
+    }
+    if stages.is_empty() {
+        Err("No envelope stages provided")?;
+    }
+    Ok(Box::new(move |data| data.set_envelope(&stages)))
+}
+
+fn knobs(matches: &ArgMatches) -> Result<Edit, Box<dyn error::Error>> {
+    let params = matches_params(matches, "PARAM")?;
+    Ok(Box::new(move |data| data.set_knobs(&params)))
+}
+
+fn fx(matches: &ArgMatches) -> Result<Edit, Box<dyn error::Error>> {
+    let fx_type = matches.value_of("TYPE").map(|t| t.parse()).transpose()?;
+    let params = matches_params(matches, "PARAM")?;
+    let active = !matches.is_present("OFF");
+    Ok(Box::new(move |data| {
+        data.set_fx(fx_type.clone(), active, &params)
+    }))
+}
+
+fn lfo(matches: &ArgMatches) -> Result<Edit, Box<dyn error::Error>> {
+    let lfo_type = matches.value_of("TYPE").map(|t| t.parse()).transpose()?;
+    let params = matches_params(matches, "PARAM")?;
+    let active = !matches.is_present("OFF");
+    Ok(Box::new(move |data| {
+        data.set_lfo(lfo_type.clone(), active, &params)
+    }))
+}
+
+fn shift(matches: &ArgMatches) -> Result<Edit, Box<dyn error::Error>> {
     let n = value_t_or_exit!(matches.value_of("N"), i8);
-    op(matches, |data| data.shift_samples(n))
+    Ok(Box::new(move |data| data.shift_samples(n)))
 }
 
-fn silence(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
+fn silence(matches: &ArgMatches) -> Result<Edit, Box<dyn error::Error>> {
     let keys = matches_keys(matches, "KEYS")?;
-    op(matches, |data| data.gain(&keys, &[-1.0]))
+    Ok(Box::new(move |data| {
+        data.volume(&keys, &[op1::Volume::Db(f32::NEG_INFINITY)], false)
+    }))
 }
 
-fn pitch(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
+fn pitch(matches: &ArgMatches) -> Result<Edit, Box<dyn error::Error>> {
     let keys = matches_keys(matches, "KEYS")?;
     let pitches = matches_pitches(matches, "PITCH")?;
-    op(matches, |data| data.pitch(&keys, &pitches))
+    let relative = matches.is_present("RELATIVE");
+    Ok(Box::new(move |data| data.pitch(&keys, &pitches, relative)))
+}
+
+fn volume(matches: &ArgMatches) -> Result<Edit, Box<dyn error::Error>> {
+    let keys = matches_keys(matches, "KEYS")?;
+    let volumes = matches_volumes(matches, "VOLUME")?;
+    let relative = matches.is_present("RELATIVE");
+    Ok(Box::new(move |data| data.volume(&keys, &volumes, relative)))
 }
 
-fn volume(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
+fn forward(matches: &ArgMatches) -> Result<Edit, Box<dyn error::Error>> {
     let keys = matches_keys(matches, "KEYS")?;
-    let gains = values_t_or_exit!(matches.values_of("VOLUME"), f32);
-    op(matches, |data| data.gain(&keys, &gains))
+    Ok(Box::new(move |data| data.reverse(&keys, false)))
 }
 
-fn forward(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
+fn reverse(matches: &ArgMatches) -> Result<Edit, Box<dyn error::Error>> {
     let keys = matches_keys(matches, "KEYS")?;
-    op(matches, |data| data.reverse(&keys, false))
+    Ok(Box::new(move |data| data.reverse(&keys, true)))
 }
 
-fn reverse(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
+fn playmode(matches: &ArgMatches) -> Result<Edit, Box<dyn error::Error>> {
     let keys = matches_keys(matches, "KEYS")?;
-    op(matches, |data| data.reverse(&keys, true))
+    let mode = value_t_or_exit!(matches.value_of("MODE"), op1::PlayMode);
+    Ok(Box::new(move |data| data.playmode(&keys, mode)))
 }
 
-fn copy(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
+fn copy(matches: &ArgMatches) -> Result<Edit, Box<dyn error::Error>> {
     let keys = matches_keys(matches, "KEYS")?;
     let src = matches_keys(matches, "SRC")?;
-    op(matches, |data| data.copy(&keys, &src))
+    Ok(Box::new(move |data| data.copy(&keys, &src)))
+}
+
+/// The `Edit` made by the editing subcommand `name`.
+fn edit_step(name: &str, matches: &ArgMatches) -> Result<Edit, Box<dyn error::Error>> {
+    match name {
+        "shift" => shift(matches),
+        "envelope" => envelope(matches),
+        "knobs" => knobs(matches),
+        "fx" => fx(matches),
+        "lfo" => lfo(matches),
+        "silence" => silence(matches),
+        "pitch" => pitch(matches),
+        "volume" => volume(matches),
+        "reverse" => reverse(matches),
+        "forward" => forward(matches),
+        "playmode" => playmode(matches),
+        "copy" => copy(matches),
+        _ => Err(format!("Unknown edit: {}", name))?,
+    }
+}
+
+/// The flags of the values that a step of `edit` may give without them, in order.
+fn step_positionals(name: &str) -> &'static [&'static str] {
+    match name {
+        "shift" => &["-n"],
+        "knobs" => &["-p"],
+        "silence" | "reverse" | "forward" => &["-k"],
+        "pitch" => &["-k", "-p"],
+        "volume" => &["-k", "-g"],
+        "playmode" => &["-k", "-m"],
+        "copy" => &["-s", "-k"],
+        _ => &[],
+    }
+}
+
+/// Parse one step of `edit`, e.g. `["pitch", "1:5", "-2"]`.
+fn parse_step(step: &[String]) -> Result<Edit, Box<dyn error::Error>> {
+    let name = step[0].as_str();
+    let command = edit_commands()
+        .into_iter()
+        .find(|c| c.get_name() == name)
+        .ok_or_else(|| format!("Unknown edit: {}", name))?;
+
+    // Leading values are given the flags they stand for, until the first flag
+    let is_flag = |arg: &str| {
+        arg.starts_with("--")
+            || (arg.len() == 2 && arg.starts_with('-') && !arg[1..].starts_with(char::is_numeric))
+    };
+    let mut args = vec![name.to_string()];
+    let mut values = step[1..].iter().peekable();
+    for flag in step_positionals(name) {
+        match values.peek() {
+            Some(value) if !is_flag(value) => {
+                args.push(flag.to_string());
+                args.push(values.next().unwrap().clone());
+            }
+            _ => break,
+        }
+    }
+    args.extend(values.cloned());
+
+    let matches = command.get_matches_from_safe(args).map_err(|e| e.message)?;
+    if matches.is_present("INPUT")
+        || matches.is_present("OUTPUT")
+        || matches.occurrences_of("OUTPUT_FILE") > 0
+        || matches.is_present("DEVICE")
+        || matches.is_present("IN_PLACE")
+    {
+        Err("Steps cannot set the input, output or device")?;
+    }
+    edit_step(name, &matches)
+}
+
+/// The tokens of an `edit` script, with a `/` after each line.
+fn script_tokens(script: &str) -> Vec<String> {
+    script
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .flat_map(|line| line.split_whitespace().chain(std::iter::once("/")))
+        .map(String::from)
+        .collect()
+}
+
+/// Parse the steps of `edit` from its tokens, returning each step's text along with its edit.
+fn parse_steps(tokens: &[String]) -> Result<Vec<(String, Edit)>, Box<dyn error::Error>> {
+    let mut steps = vec![];
+    for (i, step) in tokens
+        .split(|token| token == "/")
+        .filter(|step| !step.is_empty())
+        .enumerate()
+    {
+        let edit = parse_step(step)
+            .map_err(|e| format!("Step {} (`{}`): {}", i + 1, step.join(" "), e))?;
+        steps.push((step.join(" "), edit));
+    }
+    if steps.is_empty() {
+        Err("No edits provided")?;
+    }
+    Ok(steps)
+}
+
+fn edit(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
+    let tokens: Vec<String> = match matches.value_of("SCRIPT") {
+        Some(script) => script_tokens(&fs::read_to_string(script)?),
+        None => matches
+            .values_of("STEPS")
+            .unwrap()
+            .map(String::from)
+            .collect(),
+    };
+    let steps = parse_steps(&tokens)?;
+
+    // Edit a copy, so that the patch is left untouched unless every step succeeds
+    op(matches, |data| {
+        let mut edited = data.clone();
+        for (i, (step, edit)) in steps.iter().enumerate() {
+            log::info!("Step {}: {}", i + 1, step);
+            edit(&mut edited).map_err(|e| format!("Step {} (`{}`): {}", i + 1, step, e))?;
+        }
+        *data = edited;
+        Ok(())
+    })
 }
 
 fn dump(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
@@ -416,8 +964,19 @@ fn dump(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
 
     log::info!("Input file: {:#?}", &form);
 
-    let json = if let Some(ApplicationSpecificChunk::OP1 { data }) = form.app.first_mut() {
-        serde_json::to_vec_pretty(data)?
+    let json = if let Some(ApplicationSpecificChunk::OP1 { data, key_order }) = form.app.first_mut()
+    {
+        // Keep the patch's key order, so that `set` with the dump writes the same metadata
+        let mut json = Value::Object(data.to_json(key_order));
+        if let (true, op1::OP1Data::Drum { volume, .. }) = (matches.is_present("VOLUME_DB"), data) {
+            let db: Vec<Option<f64>> = volume
+                .iter()
+                .map(|&v| Some(op1::volume_to_db(v as f32)).filter(|db| db.is_finite()))
+                .map(|db| db.map(|db| (f64::from(db) * 100.0).round() / 100.0))
+                .collect();
+            json["volume_db"] = serde_json::to_value(db)?;
+        }
+        serde_json::to_vec_pretty(&json)?
     } else {
         Err("No OP data to dump")?
     };
@@ -425,160 +984,286 @@ fn dump(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
     use std::io::Write;
     match o {
         Output::Stdout(mut stdout) => stdout.write_all(&json)?,
-        Output::File(mut file) => file.write_all(&json)?,
+        Output::File { path, backup } => write_file(&path, backup, |file| file.write_all(&json))?,
     };
     Ok(())
 }
 
-fn set(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
-    let (i, o) = matches_io(matches)?;
-    let mut form = match i {
-        Input::Stdin(mut stdin) => read_aif(&mut stdin)?,
-        Input::File(mut file) => read_aif(&mut file)?,
-    };
+/// Print the differences between two patches, returning the exit code.
+fn diff(matches: &ArgMatches) -> Result<i32, Box<dyn error::Error>> {
+    let a = read_aif(&mut File::open(matches.value_of("A").unwrap())?)?;
+    let b = read_aif(&mut File::open(matches.value_of("B").unwrap())?)?;
+    let differences = diff::diff(&a, &b);
+    for d in differences.iter() {
+        println!(
+            "{}: {} -> {}",
+            d.field,
+            d.a.as_deref().unwrap_or("(missing)"),
+            d.b.as_deref().unwrap_or("(missing)")
+        );
+    }
+    Ok(if differences.is_empty() {
+        0
+    } else if differences.iter().any(|d| d.audio) {
+        2
+    } else {
+        1
+    })
+}
 
-    log::info!("Input file: {:#?}", &form);
+fn validate(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
+    let mut bytes = vec![];
+    match matches.value_of("INPUT") {
+        Some("-") | None => io::stdin().lock().read_to_end(&mut bytes)?,
+        Some(input) => File::open(input)?.read_to_end(&mut bytes)?,
+    };
+    let device = matches_device(matches).unwrap_or(op1::Device::OP1);
+    let findings = validate::validate(&bytes, device);
+    for finding in findings.iter() {
+        println!("{}", finding);
+    }
+    let errors = findings
+        .iter()
+        .filter(|f| f.severity == validate::Severity::Error)
+        .count();
+    if errors > 0 {
+        let noun = if errors == 1 { "error" } else { "errors" };
+        Err(format!("{} {} found", errors, noun))?;
+    }
+    Ok(())
+}
 
-    let json = fs::read(matches.value_of("JSON").unwrap())?;
-    let new_data: op1::OP1Data = serde_json::from_slice(&json)?;
+fn repair(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
+    let (i, o) = matches_io(matches)?;
+    let mut bytes = vec![];
+    match i {
+        Input::Stdin(mut stdin) => stdin.read_to_end(&mut bytes)?,
+        Input::File(mut file) => file.read_to_end(&mut bytes)?,
+    };
 
-    if let Some(ApplicationSpecificChunk::OP1 { data }) = form.app.first_mut() {
-        *data = new_data;
-    } else {
-        Err("No OP data to alter")?;
+    let repair::Repaired {
+        mut form,
+        fixes,
+        warnings,
+    } = repair::repair(&bytes)?;
+    if let Some(device) = matches_device(matches) {
+        if let Some(ApplicationSpecificChunk::OP1 { data, .. }) = form.app.first_mut() {
+            data.set_device(device)?;
+        }
+    }
+    for fix in fixes.iter() {
+        eprintln!("Fixed {}", fix);
+    }
+    for warning in warnings.iter() {
+        eprintln!("Warning: {}", warning);
+    }
+    if fixes.is_empty() && warnings.is_empty() {
+        eprintln!("Nothing to repair");
     }
 
     match o {
         Output::Stdout(mut stdout) => form.write(&mut stdout)?,
-        Output::File(mut file) => form.write(&mut file)?,
+        Output::File { path, backup } => write_file(&path, backup, |file| form.write(file))?,
     };
     Ok(())
 }
 
-fn wav_data_to_i16(data: &wav::BitDepth) -> Vec<i16> {
-    match data {
-        wav::BitDepth::Eight(d) => d.iter().map(|&x| (x as i16) << 8).collect(),
-        wav::BitDepth::Sixteen(d) => d.iter().map(|&x| x as i16).collect(),
-        wav::BitDepth::TwentyFour(d) => d.iter().map(|&x| (x >> 16) as i16).collect(),
-        wav::BitDepth::ThirtyTwoFloat(d) => d.iter().map(|&x| (x as i32 >> 16) as i16).collect(),
-        wav::BitDepth::Empty => vec![],
+fn inspect(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
+    let mut bytes = vec![];
+    match matches.value_of("INPUT") {
+        Some("-") | None => io::stdin().lock().read_to_end(&mut bytes)?,
+        Some(input) => File::open(input)?.read_to_end(&mut bytes)?,
+    };
+    let inspection = inspect::inspect(&bytes)?;
+    let mut stdout = io::stdout().lock();
+    if matches.is_present("JSON") {
+        serde_json::to_writer_pretty(&mut stdout, &inspection)?;
+        writeln!(stdout)?;
+    } else {
+        inspect::write_text(&mut stdout, &inspection)?;
     }
+    Ok(())
 }
 
-fn wav_i16_to_bytes(data: &[i16]) -> Vec<u8> {
-    let mut r = Vec::with_capacity(data.len() * 2);
-    for x in data.iter() {
-        let [a, b] = x.to_be_bytes();
-        r.push(a);
-        r.push(b);
-    }
-    r
+fn info(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
+    let form = match matches.value_of("INPUT") {
+        Some("-") | None => read_aif(&mut io::stdin().lock())?,
+        Some(input) => read_aif(&mut File::open(input)?)?,
+    };
+    let device = matches_device(matches).unwrap_or(op1::Device::OP1);
+    info::write_info(&mut io::stdout().lock(), &form, device)?;
+    Ok(())
 }
 
-fn drop_channels(data: &[i16], num_channels: usize) -> Vec<i16> {
-    let mut r = Vec::with_capacity(data.len() / num_channels);
-    let mut i = 0;
-    while i < data.len() {
-        r.push(data[i]);
-        i += num_channels;
-    }
-    r
-}
+fn set(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
+    let (i, o) = matches_io(matches)?;
+    let mut form = match i {
+        Input::Stdin(mut stdin) => read_aif(&mut stdin)?,
+        Input::File(mut file) => read_aif(&mut file)?,
+    };
 
-fn halve_bitrate(data: &[i16]) -> Vec<i16> {
-    let mut r = Vec::with_capacity(data.len() / 2);
-    let mut i = 0;
-    while i < data.len() {
-        r.push(data[i]);
-        i += 2;
-    }
-    r
-}
+    log::info!("Input file: {:#?}", &form);
+
+    let (data, key_order) = match form.app.first_mut() {
+        Some(ApplicationSpecificChunk::OP1 { data, key_order }) => (data, key_order),
+        _ => Err("No OP data to alter")?,
+    };
+    let mut json = Value::Object(data.to_json(key_order));
+    // Unknown fields are kept, but only those that were already there can be set
+    let mut known: Vec<String> = data.extra().keys().cloned().collect();
 
-fn wav_to_bytes(
-    header: &wav::Header,
-    data: &wav::BitDepth,
-    low_res: bool,
-) -> Result<Vec<u8>, &'static str> {
-    if header.sampling_rate != 44100 {
-        Err("Sample must be encoded at 44100 Hz")?;
+    if let Some(path) = matches.value_of("JSON") {
+        let doc: Value = serde_json::from_slice(&fs::read(path)?)?;
+        let replaces = doc.get("type").is_some_and(|t| Some(t) != json.get("type"));
+        match doc {
+            Value::Array(operations) => json_edit::apply_patch(&mut json, &operations)?,
+            Value::Object(object) if replaces => {
+                known.extend(object.keys().cloned());
+                json = Value::Object(object);
+            }
+            object @ Value::Object(_) => json_edit::merge(&mut json, &object),
+            _ => Err("JSON must be an object or a JSON Patch array")?,
+        }
     }
-    if header.audio_format != 1 {
-        Err("Sample must be PCM encoded")?;
+    for field in matches.values_of("FIELD").into_iter().flatten() {
+        json_edit::set_field(&mut json, field)?;
     }
-
-    let mut data = wav_data_to_i16(data);
-
-    if header.channel_count != 1 {
-        data = drop_channels(&data, header.channel_count as usize);
+    // Added by `dump --volume-db`, for reading only
+    if let Value::Object(object) = &mut json {
+        object.remove("volume_db");
     }
-    if low_res {
-        data = halve_bitrate(&data);
+
+    let (new_data, new_key_order) = op1::OP1Data::from_bytes(&serde_json::to_vec(&json)?)
+        .map_err(|e| format!("Invalid OP metadata: {}", e))?;
+    if let Some(field) = new_data.extra().keys().find(|k| !known.contains(k)) {
+        Err(format!("Unknown field `{}`", field))?;
     }
+    *data = new_data;
+    *key_order = new_key_order;
+
+    match o {
+        Output::Stdout(mut stdout) => form.write(&mut stdout)?,
+        Output::File { path, backup } => write_file(&path, backup, |file| form.write(file))?,
+    };
+    Ok(())
+}
+
+/// Detect the pitch of the samples, falling back to a note name in the input filename.
+fn detect_base_freq(samples: &[i16], input: Option<&str>) -> Option<u16> {
+    let freq = match audio::detect_pitch(samples, 44100) {
+        Some(freq) => {
+            log::info!("Detected pitch: {:.2} Hz", freq);
+            freq
+        }
+        None => {
+            let note = audio::note_from_filename(input?)?;
+            log::info!("Using note {} from filename", keys::note_name(note));
+            audio::midi_note_to_freq(note as f32)
+        }
+    };
+    Some(freq.round() as u16)
+}
 
-    Ok(wav_i16_to_bytes(&data))
+fn parse_note_range(s: &str) -> Result<(f32, f32), String> {
+    let err = || format!("Invalid note range: {} (expected e.g. F3:E5)", s);
+    let (low, high) = s.split_once(':').ok_or_else(err)?;
+    let low = keys::parse_note(low).ok_or_else(err)?;
+    let high = keys::parse_note(high).ok_or_else(err)?;
+    if low > high {
+        return Err(err());
+    }
+    Ok((low as f32, high as f32))
 }
 
 fn synth(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
     let (i, o) = matches_io(matches)?;
-    let basefreq = value_t_or_exit!(matches.value_of("BASE_FREQ"), u16);
+    let mut basefreq = value_t_or_exit!(matches.value_of("BASE_FREQ"), u16);
+    let device = matches_device(matches).unwrap_or(op1::Device::OP1);
 
-    let (header, data) = match i {
-        Input::Stdin(mut stdin) => {
-            use std::io::Read;
+    let source = match matches.value_of("MULTISAMPLE") {
+        Some(dir) => {
+            let sources = patch::read_multisample(dir)?;
+            if matches.is_present("DRUM") {
+                let name = matches.value_of("NAME").unwrap();
+                let form = patch::multisample_drum_form(sources, device, name)?;
+                match o {
+                    Output::Stdout(mut stdout) => form.write(&mut stdout)?,
+                    Output::File { path, backup } => {
+                        write_file(&path, backup, |file| form.write(file))?
+                    }
+                };
+                return Ok(());
+            }
+            let range = parse_note_range(matches.value_of("RANGE").unwrap_or("F3:E5"))?;
+            patch::best_multisample(sources, range)
+        }
+        None => {
             let mut buffer = Vec::new();
-            stdin.read_to_end(&mut buffer)?;
-            let mut cursor = io::Cursor::new(buffer);
-            wav::read(&mut cursor)?
+            {
+                use std::io::Read;
+                match i {
+                    Input::Stdin(mut stdin) => stdin.read_to_end(&mut buffer)?,
+                    Input::File(mut file) => file.read_to_end(&mut buffer)?,
+                };
+            }
+            patch::read_sample(buffer)?
         }
-        Input::File(mut file) => wav::read(&mut file)?,
     };
-
-    log::info!("WAV header: {:#?}", header);
-
-    let mut sound_data = wav_to_bytes(&header, &data, false)?;
-    let target_len = 44100 * 6 * 2; // Hz * seconds * 2 bytes
-    if sound_data.len() > target_len {
-        log::warn!("Sample is longer than 6 seconds. Truncating to fit.");
-        sound_data.resize(target_len, 0);
+    let patch::Sample {
+        samples,
+        root_note,
+        sample_loop: stored_loop,
+    } = source;
+    if matches.is_present("DETECT_PITCH") {
+        basefreq = detect_base_freq(&samples, matches.value_of("INPUT")).unwrap_or_else(|| {
+            log::warn!("Could not detect pitch. Using {} Hz.", basefreq);
+            basefreq
+        });
+    } else if matches.occurrences_of("BASE_FREQ") == 0 {
+        if let Some(note) = root_note {
+            log::info!("Using root note {:.2} of the source", note);
+            basefreq = audio::midi_note_to_freq(note).round() as u16;
+        }
     }
 
-    let mut form = chunks::FormChunk::default();
-    form.common.num_sample_frames = sound_data.len() as u32 / 2;
-    let mut op_data = op1::OP1Data::default_sampler();
-    if let op1::OP1Data::Sampler {
-        ref mut base_freq, ..
-    } = op_data
-    {
-        *base_freq = basefreq;
-    }
-    form.app
-        .push(chunks::ApplicationSpecificChunk::OP1 { data: op_data });
-    form.sound = Some(chunks::SoundDataChunk {
-        size: sound_data.len() as i32 + 8,
-        offset: 0,
-        block_size: 0,
-        sound_data,
-    });
+    let form = patch::synth_form(
+        samples,
+        stored_loop,
+        basefreq,
+        matches.is_present("LOOP"),
+        device,
+        matches.value_of("NAME").unwrap(),
+    )?;
 
     match o {
         Output::Stdout(mut stdout) => form.write(&mut stdout)?,
-        Output::File(mut file) => form.write(&mut file)?,
+        Output::File { path, backup } => write_file(&path, backup, |file| form.write(file))?,
     };
     Ok(())
 }
 
 fn drum(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
-    let shift = value_t_or_exit!(matches.value_of("SHIFT"), usize);
-    let octav = value_t_or_exit!(matches.value_of("OCTAVE"), u8);
-    let copy_remaining = matches.is_present("COPY_REMAINING");
-    let low_res = matches.is_present("LOW_RES");
-    let pitch_shift_remaining = matches.is_present("PITCH_SHIFT_REMAINING");
+    let options = patch::DrumOptions {
+        shift: value_t_or_exit!(matches.value_of("SHIFT"), usize),
+        octave: value_t_or_exit!(matches.value_of("OCTAVE"), u8),
+        copy_remaining: matches.is_present("COPY_REMAINING"),
+        pitch_shift_remaining: matches.is_present("PITCH_SHIFT_REMAINING"),
+        low_res: matches.is_present("LOW_RES"),
+        use_root_notes: matches.is_present("USE_ROOT_NOTES"),
+    };
     let use_input_ordering = matches.is_present("USE_INPUT_ORDERING");
+    let device = matches_device(matches).unwrap_or(op1::Device::OP1);
 
-    if octav < 1 || octav > 10 {
-        Err("OCTAVE must be between 1 and 10")?;
+    if let Some(path) = matches.value_of("MANIFEST") {
+        let path = std::path::Path::new(path);
+        let form = manifest::Manifest::read(path)?.build(
+            path.parent().unwrap_or_else(|| std::path::Path::new(".")),
+            device,
+            matches.value_of("NAME").unwrap(),
+        )?;
+        return write_patch(&form, matches.value_of("OUTPUT_FILE").unwrap());
     }
+
     let mut input_files: Vec<&str> = matches.values_of("INPUT_FILES").unwrap().collect();
     let output_file = if let Some(&"-") = input_files.last() {
         input_files.pop();
@@ -591,82 +1276,136 @@ fn drum(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
         input_files.sort(); // TODO sort_by?
     }
 
-    if shift + input_files.len() > 24 {
-        Err("Shift value N plus number of input files must not exceed 24 keys")?;
-    }
-
-    let mut sound_data: Vec<u8> = vec![];
-    let mut starts: [u32; 24] = [0; 24];
-    let mut ends: [u32; 24] = [0; 24];
-    let mut pitches: [i16; 24] = [if low_res { 12 * -512 } else { 0 }; 24];
-    let max_len = 44100 * 12 * 2; // Hz * seconds * 2 bytes
-    let mut i = shift;
-    for input in input_files.iter() {
-        let mut file = File::open(input)?;
-        let (header, data) = wav::read(&mut file)?;
-        log::info!("{}, header: {:#?}", input, header);
-        let data = wav_to_bytes(&header, &data, low_res)?;
-        starts[i] = sound_data.len() as u32 * 2029; // Confusing magic number
-        sound_data.extend(&data);
-        ends[i] = sound_data.len() as u32 * 2029;
-        if sound_data.len() > max_len {
-            Err("Samples cannot add up to more than 12 seconds")?;
-        }
-        i += 1;
+    let form = patch::drum_form(
+        &input_files,
+        &options,
+        device,
+        matches.value_of("NAME").unwrap(),
+    )?;
+
+    write_patch(&form, output_file)
+}
+
+/// Write a patch to `output`, or to STDOUT if it is `-`.
+fn write_patch(form: &chunks::FormChunk, output: &str) -> Result<(), Box<dyn error::Error>> {
+    match output {
+        "-" => form.write(&mut io::stdout())?,
+        output => write_file(Path::new(output), false, |file| form.write(file))?,
+    };
+    Ok(())
+}
+
+fn batch(matches: &ArgMatches) -> Result<(), Box<dyn error::Error>> {
+    let input = std::path::Path::new(matches.value_of("INPUT_DIR").unwrap());
+    let output = std::path::Path::new(matches.value_of("OUTPUT_DIR").unwrap());
+    let device = matches_device(matches).unwrap_or(op1::Device::OP1);
+    let patch_type = if matches.is_present("SYNTH") {
+        batch::PatchType::Synth
+    } else {
+        batch::PatchType::Drum
+    };
+    let threads = match matches.value_of("JOBS") {
+        Some(_) => value_t_or_exit!(matches.value_of("JOBS"), usize),
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+
+    let jobs = batch::find_jobs(input, output)?;
+    if jobs.is_empty() {
+        Err(format!(
+            "No samples or kit manifests found in {}",
+            input.display()
+        ))?;
+    }
+    let outcomes = batch::run(jobs, patch_type, device, threads);
+    batch::print_summary(&outcomes);
+
+    let failed = outcomes.iter().filter(|o| o.result.is_err()).count();
+    if failed > 0 {
+        Err(format!("{} of {} patches failed", failed, outcomes.len()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(steps: &str) -> Vec<String> {
+        steps.split_whitespace().map(String::from).collect()
     }
 
-    if pitch_shift_remaining || copy_remaining {
-        let last = shift + input_files.len() - 1;
-        for i in 0..shift {
-            starts[i] = starts[shift];
-            ends[i] = ends[shift];
+    /// Apply the steps to a default drum patch.
+    fn apply(steps: &str) -> Result<op1::OP1Data, Box<dyn error::Error>> {
+        let mut data = op1::OP1Data::default_drum(op1::Device::OP1);
+        for (_, edit) in parse_steps(&tokens(steps))? {
+            edit(&mut data)?;
         }
-        for i in last..24 {
-            starts[i] = starts[last];
-            ends[i] = ends[last];
+        Ok(data)
+    }
+
+    fn error(steps: &str) -> String {
+        match parse_steps(&tokens(steps)) {
+            Ok(_) => panic!("`{}` parsed", steps),
+            Err(e) => e.to_string(),
         }
+    }
 
-        if pitch_shift_remaining {
-            for i in 0..shift {
-                pitches[i] += (shift - i) as i16 * -512;
-            }
-            for i in last..24 {
-                pitches[i] += (i - last) as i16 * 512;
+    #[test]
+    fn steps_are_split_at_slashes() {
+        let steps = parse_steps(&tokens("/ shift 7 / / pitch 1:5 -2 /")).unwrap();
+        let steps: Vec<&str> = steps.iter().map(|(step, _)| step.as_str()).collect();
+        assert_eq!(steps, ["shift 7", "pitch 1:5 -2"]);
+        assert_eq!(error("/ /"), "No edits provided");
+    }
+
+    #[test]
+    fn values_can_be_given_without_flags() {
+        let data = apply("pitch 1:5 -2 / volume 2 -3dB / copy 1 8").unwrap();
+        let flagged = apply("pitch -k 1:5 -p -2 / volume -k 2 -g -3dB / copy -s 1 -k 8").unwrap();
+        assert_eq!(data.to_json(&[]), flagged.to_json(&[]));
+        match data {
+            op1::OP1Data::Drum { pitch, volume, .. } => {
+                assert_eq!(pitch[..6], [-1024, -1024, -1024, -1024, -1024, 0]);
+                assert_eq!(pitch[7], -1024);
+                assert!(volume[1] < volume[2]);
             }
+            _ => unreachable!(),
         }
     }
 
-    let mut form = chunks::FormChunk::default();
-    form.common.num_sample_frames = sound_data.len() as u32 / 2;
-    let mut op_data = op1::OP1Data::default_drum();
-    if let op1::OP1Data::Drum {
-        ref mut octave,
-        ref mut start,
-        ref mut end,
-        ref mut pitch,
-        ..
-    } = op_data
-    {
-        *octave = octav - 5;
-        *start = starts;
-        *end = ends;
-        *pitch = pitches;
-    }
-    form.app
-        .push(chunks::ApplicationSpecificChunk::OP1 { data: op_data });
-    form.sound = Some(chunks::SoundDataChunk {
-        size: sound_data.len() as i32 + 8,
-        offset: 0,
-        block_size: 0,
-        sound_data,
-    });
+    #[test]
+    fn flags_follow_the_values() {
+        let data = apply("volume all -3dB -r").unwrap();
+        let flagged = apply("volume -k all -g -3dB -r").unwrap();
+        assert_eq!(data.to_json(&[]), flagged.to_json(&[]));
+        assert!(error("pitch 1 -p 2 3").contains("Step 1 (`pitch 1 -p 2 3`)"));
+    }
 
-    if output_file == "-" {
-        form.write(&mut io::stdout())?;
-    } else {
-        let mut file = File::create(output_file)?;
-        form.write(&mut file)?;
+    #[test]
+    fn invalid_steps() {
+        assert_eq!(
+            error("shift 7 / frobnicate 3"),
+            "Step 2 (`frobnicate 3`): Unknown edit: frobnicate"
+        );
+        for step in &[
+            "shift 7 -o out.aif",
+            "shift 7 --device opz",
+            "shift 7 in.aif",
+        ] {
+            assert!(
+                error(step).ends_with("Steps cannot set the input, output or device"),
+                "{}",
+                step
+            );
+        }
+        // `--in-place` needs an input, which steps can't give
+        assert!(error("shift 7 -i").contains("<INPUT>"));
+        assert!(error("pitch 1").starts_with("Step 1 (`pitch 1`)"));
     }
 
-    Ok(())
+    #[test]
+    fn scripts_have_a_step_per_line() {
+        let script = "# Transpose\n\nshift 7\n  pitch 1:5 -2  \n";
+        assert_eq!(script_tokens(script), tokens("shift 7 / pitch 1:5 -2 /"));
+    }
 }
@@ -0,0 +1,351 @@
+// Kit manifests: a TOML description of a drum patch, so that it can be rebuilt from files
+// kept under version control.
+//
+// ```toml
+// name = "kit"
+// octave = 5
+//
+// [fx]
+// type = "spring"
+// params = { mix = 0.4 }
+//
+// [[key]]
+// key = "F1"
+// file = "kick.wav"
+// end = "250ms"
+// volume = "+2dB"
+// playmode = "oneshot"
+// ```
+
+use crate::chunks::FormChunk;
+use crate::keys;
+use crate::op1::{self, Device, OP1Data, Volume};
+use crate::parse;
+use crate::patch;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A file, with the start and end of the audio used from it, in samples.
+type Region = (PathBuf, usize, usize);
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub name: Option<String>,
+    /// From 1 to 10, as with `drum --octave`.
+    pub octave: Option<u8>,
+    pub fx: Option<Effect>,
+    pub lfo: Option<Effect>,
+    pub dyna_env: Option<[u16; 8]>,
+    #[serde(default, rename = "key")]
+    pub keys: Vec<KeyEntry>,
+}
+
+/// An FX or LFO, with parameters from 0 to 1.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Effect {
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    #[serde(default = "default_active")]
+    pub active: bool,
+    #[serde(default)]
+    pub params: BTreeMap<String, f32>,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyEntry {
+    /// A key number or note name, as with `--keys`.
+    pub key: Quantity,
+    /// Relative to the manifest.
+    pub file: PathBuf,
+    /// Times in the file, e.g. `"120ms"`, or a number of milliseconds.
+    pub start: Option<Quantity>,
+    pub end: Option<Quantity>,
+    /// E.g. `"-2st"`, `"35c"`, or a number of semitones.
+    pub pitch: Option<Quantity>,
+    /// E.g. `"-3dB"`, `"-inf"`, or a number of dB.
+    pub volume: Option<Quantity>,
+    #[serde(default)]
+    pub reverse: bool,
+    pub playmode: Option<String>,
+}
+
+/// A value that may be given either as a string with a unit, or as a bare number.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Quantity {
+    Number(f64),
+    Text(String),
+}
+
+impl Quantity {
+    /// The value as a string, with `unit` appended if it is a bare number.
+    fn with_unit(&self, unit: &str) -> String {
+        match self {
+            Self::Number(n) => format!("{}{}", n, unit),
+            Self::Text(s) => s.clone(),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn read(path: &Path) -> Result<Self, Box<dyn error::Error>> {
+        let text = fs::read_to_string(path)?;
+        Ok(toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?)
+    }
+
+    /// Build the drum patch described by the manifest. Files are relative to `dir`.
+    pub fn build(
+        &self,
+        dir: &Path,
+        device: Device,
+        default_name: &str,
+    ) -> Result<FormChunk, Box<dyn error::Error>> {
+        let octave = self.octave.unwrap_or(5);
+        if !(1..=10).contains(&octave) {
+            Err(format!("Octave {} out of range (1-10)", octave))?;
+        }
+
+        // The audio of each key, shared between keys that use the same region of a file
+        let mut regions: Vec<(Region, (u32, u32))> = vec![];
+        let mut sound_data: Vec<u8> = vec![];
+        let mut starts: [u32; 24] = [0; 24];
+        let mut ends: [u32; 24] = [0; 24];
+        let mut assigned: Vec<u8> = vec![];
+        let max_len = 44100 * device.max_drum_seconds() * 2; // Hz * seconds * 2 bytes
+        for entry in self.keys.iter() {
+            let key = keys::parse_key(&entry.key.with_unit(""))?;
+            if assigned.contains(&key) {
+                Err(format!(
+                    "Key {} is assigned more than once",
+                    keys::key_name(key)
+                ))?;
+            }
+            assigned.push(key);
+
+            let path = dir.join(&entry.file);
+            let samples = patch::read_sample(fs::read(&path)?)?.samples;
+            let to_sample = |time: &Quantity| -> Result<usize, String> {
+                let ms = parse::parse_time(&time.with_unit("ms"))?;
+                Ok(((ms * 44.1).round() as usize).min(samples.len()))
+            };
+            let start = entry
+                .start
+                .as_ref()
+                .map(to_sample)
+                .transpose()?
+                .unwrap_or(0);
+            let end = entry
+                .end
+                .as_ref()
+                .map(to_sample)
+                .transpose()?
+                .unwrap_or(samples.len());
+            if start >= end {
+                Err(format!(
+                    "Key {}: the region of {} is empty",
+                    keys::key_name(key),
+                    path.display()
+                ))?;
+            }
+
+            let region = (path, start, end);
+            let (key_start, key_end) = match regions.iter().find(|(r, _)| *r == region) {
+                Some((_, offsets)) => *offsets,
+                None => {
+                    let key_start = sound_data.len() as u32 * op1::POSITION_PER_BYTE;
+                    sound_data.extend(patch::wav_i16_to_bytes(&samples[start..end]));
+                    let offsets = (key_start, sound_data.len() as u32 * op1::POSITION_PER_BYTE);
+                    regions.push((region, offsets));
+                    offsets
+                }
+            };
+            starts[key as usize - 1] = key_start;
+            ends[key as usize - 1] = key_end;
+        }
+        if sound_data.len() > max_len {
+            Err(format!(
+                "Samples cannot add up to more than {} seconds",
+                device.max_drum_seconds()
+            ))?;
+        }
+
+        let mut op_data = OP1Data::default_drum(device);
+        op_data.set_name(device, self.name.as_deref().unwrap_or(default_name))?;
+        if let OP1Data::Drum {
+            octave: ref mut oct,
+            ref mut start,
+            ref mut end,
+            ref mut dyna_env,
+            ..
+        } = op_data
+        {
+            *oct = octave as i8 - 5;
+            *start = starts;
+            *end = ends;
+            if let Some(env) = self.dyna_env {
+                *dyna_env = env;
+            }
+        }
+
+        for entry in self.keys.iter() {
+            let key = [keys::parse_key(&entry.key.with_unit(""))?];
+            if let Some(pitch) = &entry.pitch {
+                op_data.pitch(&key, &[parse::parse_pitch(&pitch.with_unit(""))?], false)?;
+            }
+            if let Some(volume) = &entry.volume {
+                let volume = match volume {
+                    Quantity::Number(db) => Volume::Db(*db as f32),
+                    Quantity::Text(s) => parse::parse_volume(s, false)?,
+                };
+                op_data.volume(&key, &[volume], false)?;
+            }
+            op_data.reverse(&key, entry.reverse)?;
+            if let Some(playmode) = &entry.playmode {
+                op_data.playmode(&key, playmode.parse()?)?;
+            }
+        }
+
+        if let Some(fx) = &self.fx {
+            let kind = fx.kind.as_ref().map(|t| t.parse()).transpose()?;
+            op_data.set_fx(kind, fx.active, &params(&fx.params))?;
+        }
+        if let Some(lfo) = &self.lfo {
+            let kind = lfo.kind.as_ref().map(|t| t.parse()).transpose()?;
+            op_data.set_lfo(kind, lfo.active, &params(&lfo.params))?;
+        }
+
+        Ok(patch::patch_form(op_data, sound_data))
+    }
+}
+
+fn params(params: &BTreeMap<String, f32>) -> Vec<(String, f32)> {
+    params.iter().map(|(k, v)| (k.clone(), *v)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunks::ApplicationSpecificChunk;
+
+    /// Write a WAV of `len` samples to the temporary directory, returning its file name.
+    fn temp_wav(name: &str, len: usize) -> String {
+        let name = format!("{}-{}.wav", name, std::process::id());
+        let header = wav::Header::new(1, 1, 44100, 16);
+        let data = wav::BitDepth::Sixteen((0..len).map(|i| i as i16).collect());
+        let mut file = fs::File::create(std::env::temp_dir().join(&name)).unwrap();
+        wav::write(header, &data, &mut file).unwrap();
+        name
+    }
+
+    fn build(manifest: &str) -> Result<OP1Data, Box<dyn error::Error>> {
+        let manifest: Manifest = toml::from_str(manifest)?;
+        let form = manifest.build(&std::env::temp_dir(), Device::OP1, "default")?;
+        match form.app.into_iter().next() {
+            Some(ApplicationSpecificChunk::OP1 { data, .. }) => Ok(data),
+            _ => panic!("No OP data"),
+        }
+    }
+
+    fn error(manifest: &str) -> String {
+        match build(manifest) {
+            Ok(_) => panic!("`{}` built", manifest),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn builds_a_kit() {
+        let file = temp_wav("manifest-kit", 44100);
+        let data = build(&format!(
+            r#"
+            name = "kit"
+            octave = 7
+            [fx]
+            type = "spring"
+            [[key]]
+            key = "F1"
+            file = "{0}"
+            end = 500
+            pitch = "-2st"
+            [[key]]
+            key = 2
+            file = "{0}"
+            end = "500ms"
+            "#,
+            file
+        ))
+        .unwrap();
+        assert_eq!(data.name(), "kit");
+        assert_eq!(data.octave(), 2);
+        match data {
+            OP1Data::Drum {
+                start, end, pitch, ..
+            } => {
+                // Both keys use the same region, which is stored once
+                assert_eq!((start[0], end[0]), (0, 22050 * 2 * op1::POSITION_PER_BYTE));
+                assert_eq!((start[1], end[1]), (start[0], end[0]));
+                assert_eq!(pitch[0], -1024);
+                assert_eq!(end[2], 0);
+            }
+            _ => panic!("Not a drum patch"),
+        }
+        fs::remove_file(std::env::temp_dir().join(file)).unwrap();
+    }
+
+    #[test]
+    fn octave_is_from_1_to_10() {
+        assert!(build("octave = 1").is_ok());
+        assert!(build("octave = 10").is_ok());
+        assert_eq!(error("octave = 0"), "Octave 0 out of range (1-10)");
+        assert_eq!(error("octave = 11"), "Octave 11 out of range (1-10)");
+    }
+
+    #[test]
+    fn keys_are_assigned_once() {
+        let file = temp_wav("manifest-twice", 100);
+        let manifest = format!(
+            "[[key]]\nkey = \"F1\"\nfile = \"{0}\"\n[[key]]\nkey = 1\nfile = \"{0}\"\n",
+            file
+        );
+        assert_eq!(error(&manifest), "Key F1 is assigned more than once");
+        fs::remove_file(std::env::temp_dir().join(file)).unwrap();
+    }
+
+    #[test]
+    fn regions_are_checked() {
+        let file = temp_wav("manifest-regions", 44100);
+        let manifest = format!(
+            "[[key]]\nkey = 1\nfile = \"{}\"\nstart = 300\nend = 200\n",
+            file
+        );
+        assert!(error(&manifest).ends_with("is empty"));
+        fs::remove_file(std::env::temp_dir().join(file)).unwrap();
+
+        let file = temp_wav("manifest-long", 44100 * 7);
+        let manifest = format!(
+            "[[key]]\nkey = 1\nfile = \"{0}\"\n[[key]]\nkey = 2\nfile = \"{0}\"\nend = 6999\n",
+            file
+        );
+        assert_eq!(
+            error(&manifest),
+            "Samples cannot add up to more than 12 seconds"
+        );
+        fs::remove_file(std::env::temp_dir().join(file)).unwrap();
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(error("octave = 5\ntempo = 120").contains("unknown field `tempo`"));
+        assert!(error("[[key]]\nkey = 1\nfile = \"a.wav\"\nloop = true").contains("loop"));
+    }
+}
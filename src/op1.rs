@@ -1,30 +1,175 @@
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{fmt, str};
 
-#[derive(Debug, Serialize, Deserialize)]
+pub const PITCH_STEPS_PER_SEMITONE: f32 = 512.0;
+pub const MAX_PITCH: f32 = 48.0 * PITCH_STEPS_PER_SEMITONE;
+
+const UNITY_VOLUME: f32 = 8192.0;
+pub const MAX_VOLUME: f32 = 16384.0;
+
+pub const MAX_PARAM: u16 = 32767;
+
+/// The FX and LFO parameters of new patches, which are also used when the FX or LFO type is
+/// changed: the parameters of one type mean something else under another.
+const DEFAULT_FX_PARAMS: [u16; 8] = [8000; 8];
+const DEFAULT_LFO_PARAMS: [u16; 8] = [16000, 0, 0, 16000, 0, 0, 0, 0];
+
+/// The `start` and `end` of drum keys are byte offsets into the sound data, multiplied by this.
+pub const POSITION_PER_BYTE: u32 = 2029;
+
+fn normalised_param(name: &str, value: f32) -> Result<u16, String> {
+    if !(0.0..=1.0).contains(&value) {
+        return Err(format!(
+            "Parameter {} value {} out of range (0-1)",
+            name, value
+        ));
+    }
+    Ok((value * MAX_PARAM as f32).round() as u16)
+}
+
+/// The stages of the envelope stored at the start of `adsr`.
+pub const ENVELOPE_NAMES: &[&str] = &["attack", "decay", "sustain", "release"];
+const MAX_ENVELOPE_MS: f32 = 10000.0;
+
+/// The sampler's parameters stored at the start of `knobs`. The start, end and loop points are
+/// positions within the sample.
+pub const SAMPLER_KNOB_NAMES: &[&str] = &["start", "loop_start", "loop_end", "end", "hold"];
+
+/// Envelope times grow with the square of the stored value, up to 10 seconds.
+pub fn ms_to_envelope(ms: f32) -> Result<u16, String> {
+    if !(0.0..=MAX_ENVELOPE_MS).contains(&ms) {
+        return Err(format!(
+            "Envelope time {}ms out of range (0-{}ms)",
+            ms, MAX_ENVELOPE_MS
+        ));
+    }
+    Ok(((ms / MAX_ENVELOPE_MS).sqrt() * MAX_PARAM as f32).round() as u16)
+}
+
+pub fn envelope_to_ms(value: u16) -> f32 {
+    (value as f32 / MAX_PARAM as f32).powi(2) * MAX_ENVELOPE_MS
+}
+
+/// The device a patch is for. Both devices share the OP-1's patch format, but differ in their
+/// limits and in the metadata they write.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Device {
+    OP1,
+    OPZ,
+}
+
+impl Device {
+    /// The maximum total length of the samples in a drum patch, in seconds. The OP-Z takes the
+    /// same lengths as the OP-1.
+    pub fn max_drum_seconds(self) -> usize {
+        match self {
+            Self::OP1 => 12,
+            Self::OPZ => 12,
+        }
+    }
+
+    /// The maximum length of the sample in a synth patch, in seconds.
+    pub fn max_synth_seconds(self) -> usize {
+        match self {
+            Self::OP1 => 6,
+            Self::OPZ => 6,
+        }
+    }
+
+    fn drum_version(self) -> u8 {
+        match self {
+            Self::OP1 => 1,
+            Self::OPZ => 2,
+        }
+    }
+
+    /// Patch names must be ASCII. The OP-Z only displays the first 16 characters of a name, so
+    /// it rejects anything longer.
+    pub fn check_name(self, name: &str) -> Result<(), String> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+            return Err(format!(
+                "Invalid name {:?}: names must be printable ASCII",
+                name
+            ));
+        }
+        if self == Self::OPZ && name.len() > 16 {
+            return Err(format!(
+                "Invalid name {:?}: OP-Z names must be at most 16 characters",
+                name
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::OP1 => "op1",
+            Self::OPZ => "opz",
+        })
+    }
+}
+
+impl str::FromStr for Device {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "op1" => Ok(Self::OP1),
+            "opz" => Ok(Self::OPZ),
+            _ => Err(format!("Invalid device: {}", s)),
+        }
+    }
+}
+
+/// A per-key volume, either as the raw value stored in the patch or in decibels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Volume {
+    Raw(f32),
+    Db(f32),
+}
+
+/// The OP-1 applies the square of the stored volume as gain: 0 is -inf, 8192 is unity and
+/// 16384 is +12 dB.
+pub fn volume_to_db(volume: f32) -> f32 {
+    40.0 * (volume / UNITY_VOLUME).log10()
+}
+
+pub fn db_to_volume(db: f32) -> f32 {
+    UNITY_VOLUME * 10f32.powf(db / 40.0)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant, clippy::upper_case_acronyms)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum OP1Data {
     Drum {
         name: String,     // "user"
         drum_version: u8, // 1
-        octave: u8,       // 0
+        octave: i8,       // 0
         start: [u32; 24],
         end: [u32; 24],
-        pitch: [i16; 24],    // -24567/0/24567 512 per semitone; -48 to +48
-        reverse: [u16; 24],  // 8192/16384
-        volume: [u16; 24],   // 0/8192/16384
-        playmode: [u16; 24], // 0/8192/16384
-        dyna_env: [u16; 8],  // 0-8182?
+        pitch: [i16; 24],   // -24567/0/24567 512 per semitone; -48 to +48
+        reverse: [u16; 24], // 8192/16384
+        volume: [u16; 24],  // 0/8192/16384
+        playmode: [PlayMode; 24],
+        dyna_env: [u16; 8], // 0-8182?
         lfo_active: bool,
         lfo_type: LFOType,
         lfo_params: [u16; 8], // 0-16000?
         fx_active: bool,
         fx_type: FXType,
         fx_params: [u16; 8], // 0-16000?
+        #[serde(flatten)]
+        extra: Map<String, Value>, // Any fields we don't know about
     },
     Sampler {
         name: String,      // "user"
         synth_version: u8, // 2
-        octave: u8,        // 0
+        octave: i8,        // 0
         base_freq: u16,    // 440
         adsr: [u16; 8],    // 0 - 32767
         knobs: [u16; 8],   // 0 - 32767
@@ -34,32 +179,64 @@ pub enum OP1Data {
         fx_active: bool,
         fx_type: FXType,
         fx_params: [u16; 8], // 0-16000?
+        #[serde(flatten)]
+        extra: Map<String, Value>, // Any fields we don't know about
     },
+    Cluster(SynthData),
+    Digital(SynthData),
+    Dimension(SynthData),
+    DNA(SynthData),
+    DSynth(SynthData),
+    FM(SynthData),
+    Phase(SynthData),
+    Pulse(SynthData),
+    String(SynthData),
+    Voltage(SynthData),
+}
+
+/// The fields of a synth patch for every engine other than the sampler. The meaning of `adsr`
+/// is shared between engines, while `knobs` holds the engine's four encoders and their
+/// alternate settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SynthData {
+    pub name: String,      // "user"
+    pub synth_version: u8, // 2
+    pub octave: i8,        // 0
+    pub adsr: [u16; 8],    // 0 - 32767
+    pub knobs: [u16; 8],   // 0 - 32767
+    pub lfo_active: bool,
+    pub lfo_type: LFOType,
+    pub lfo_params: [u16; 8], // 0-16000?
+    pub fx_active: bool,
+    pub fx_type: FXType,
+    pub fx_params: [u16; 8], // 0-16000?
+    #[serde(flatten)]
+    pub extra: Map<String, Value>, // Any fields we don't know about
 }
 
 impl OP1Data {
-    pub fn default_drum() -> Self {
+    pub fn default_drum(device: Device) -> Self {
         Self::Drum {
             name: "user".to_string(),
-            drum_version: 1,
+            drum_version: device.drum_version(),
             octave: 0,
             start: [0; 24],
             end: [0; 24],
             pitch: [0; 24],
             reverse: [8192; 24],
             volume: [8192; 24],
-            playmode: [8192; 24],
+            playmode: [PlayMode::OneShot; 24],
             dyna_env: [0, 8192, 0, 8192, 0, 0, 0, 0],
             fx_active: false,
             fx_type: FXType::Delay,
-            fx_params: [8000; 8],
+            fx_params: DEFAULT_FX_PARAMS,
             lfo_active: false,
             lfo_type: LFOType::Tremolo,
-            lfo_params: [16000, 0, 0, 16000, 0, 0, 0, 0],
+            lfo_params: DEFAULT_LFO_PARAMS,
+            extra: Map::new(),
         }
     }
 
-    #[allow(dead_code)]
     pub fn default_sampler() -> Self {
         Self::Sampler {
             name: "user".to_string(),
@@ -70,15 +247,101 @@ impl OP1Data {
             knobs: [0, 0, 22501, 22501, 8192, 0, 6183, 8192],
             fx_active: false,
             fx_type: FXType::Delay,
-            fx_params: [8000; 8],
+            fx_params: DEFAULT_FX_PARAMS,
             lfo_active: false,
             lfo_type: LFOType::Tremolo,
-            lfo_params: [16000, 0, 0, 16000, 0, 0, 0, 0],
+            lfo_params: DEFAULT_LFO_PARAMS,
+            extra: Map::new(),
+        }
+    }
+
+    /// The name of the patch's engine, as stored in its `type` field.
+    pub fn engine(&self) -> &'static str {
+        match self {
+            Self::Drum { .. } => "drum",
+            Self::Sampler { .. } => "sampler",
+            Self::Cluster(_) => "cluster",
+            Self::Digital(_) => "digital",
+            Self::Dimension(_) => "dimension",
+            Self::DNA(_) => "dna",
+            Self::DSynth(_) => "dsynth",
+            Self::FM(_) => "fm",
+            Self::Phase(_) => "phase",
+            Self::Pulse(_) => "pulse",
+            Self::String(_) => "string",
+            Self::Voltage(_) => "voltage",
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Drum { name, .. } | Self::Sampler { name, .. } => name,
+            _ => &self.synth().unwrap().name,
+        }
+    }
+
+    /// The fields that aren't otherwise understood, which are kept as they are.
+    pub fn extra(&self) -> &Map<String, Value> {
+        match self {
+            Self::Drum { extra, .. } | Self::Sampler { extra, .. } => extra,
+            _ => &self.synth().unwrap().extra,
         }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut vec = serde_json::to_vec(self).unwrap();
+    pub fn octave(&self) -> i8 {
+        match self {
+            Self::Drum { octave, .. } | Self::Sampler { octave, .. } => *octave,
+            _ => self.synth().unwrap().octave,
+        }
+    }
+
+    pub fn set_name(&mut self, device: Device, new_name: &str) -> Result<(), String> {
+        device.check_name(new_name)?;
+        match self {
+            Self::Drum { name, .. } | Self::Sampler { name, .. } => *name = new_name.to_string(),
+            _ => self.synth_mut().unwrap().name = new_name.to_string(),
+        }
+        Ok(())
+    }
+
+    /// Convert the metadata to the dialect of the given device, checking that its name suits the
+    /// device.
+    pub fn set_device(&mut self, device: Device) -> Result<(), String> {
+        device.check_name(self.name())?;
+        if let Self::Drum { drum_version, .. } = self {
+            *drum_version = device.drum_version();
+        }
+        Ok(())
+    }
+
+    /// Parse from JSON, returning the order of its keys along with the data.
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Self, Vec<String>), serde_json::Error> {
+        let json: Value = serde_json::from_slice(bytes)?;
+        let key_order = json
+            .as_object()
+            .map_or(vec![], |o| o.keys().cloned().collect());
+        Ok((serde_json::from_value(json)?, key_order))
+    }
+
+    /// Convert to a JSON object, with the keys in `key_order` first and in that order.
+    pub fn to_json(&self, key_order: &[String]) -> Map<String, Value> {
+        let mut json = match serde_json::to_value(self).unwrap() {
+            Value::Object(json) => json,
+            _ => unreachable!(),
+        };
+        let mut ordered = Map::new();
+        for key in key_order.iter() {
+            if let Some(value) = json.remove(key) {
+                ordered.insert(key.clone(), value);
+            }
+        }
+        ordered.append(&mut json);
+        ordered
+    }
+
+    /// Serialize to JSON, with the keys in `key_order` first and in that order.
+    pub fn to_bytes(&self, key_order: &[String]) -> Vec<u8> {
+        let mut vec = serde_json::to_vec(&self.to_json(key_order)).unwrap();
         if vec.len() % 2 == 1 {
             vec.push(0);
         }
@@ -86,11 +349,10 @@ impl OP1Data {
     }
 
     pub fn shift_samples(&mut self, n: i8) -> Result<(), String> {
-        if n > 23 || n < -23 {
+        if !(-23..=23).contains(&n) {
             return Err("Cannot shift beyond 23 semitones".to_string());
         }
         match self {
-            Self::Sampler { .. } => return Err("Cannot shift a synth sample".to_string()),
             Self::Drum {
                 start,
                 end,
@@ -109,7 +371,7 @@ impl OP1Data {
                     volume.rotate_right(n);
                     playmode.rotate_right(n);
                 } else {
-                    let n = n.abs() as usize;
+                    let n = n.unsigned_abs() as usize;
                     start.rotate_left(n);
                     end.rotate_left(n);
                     pitch.rotate_left(n);
@@ -118,78 +380,402 @@ impl OP1Data {
                     playmode.rotate_left(n);
                 }
             }
+            _ => return Err("Cannot shift a synth patch".to_string()),
         }
 
         Ok(())
     }
 
-    pub fn pitch(&mut self, keys: &[u8], pitches: &[i8]) -> Result<(), String> {
+    /// Set the pitch of the given keys, in semitones. When `relative` is set, the pitches are
+    /// added to the current pitch of each key.
+    pub fn pitch(&mut self, keys: &[u8], pitches: &[f32], relative: bool) -> Result<(), String> {
         let mut p = 0;
         if pitches.is_empty() {
             return Err("No pitch provided".to_string());
         }
 
         match self {
-            Self::Sampler { .. } => return Err("Cannot pitch a synth sample".to_string()),
             Self::Drum { pitch, .. } => {
                 for &key in keys.iter() {
-                    if key < 1 || key > 24 {
+                    if !(1..=24).contains(&key) {
                         return Err(format!("Key {} out of range (1-24)", key));
                     }
-                    let ptch = pitches[p];
-                    if ptch < -48 || ptch > 48 {
-                        return Err(format!("Pitch {} out of range (-48-+48)", ptch));
+                    let current = pitch[key as usize - 1];
+                    let mut ptch = pitches[p] * PITCH_STEPS_PER_SEMITONE;
+                    if relative {
+                        ptch += current as f32;
+                    }
+                    if ptch.abs() > MAX_PITCH {
+                        log::warn!(
+                            "Pitch {:+.2} on key {} out of range (-48-+48), clamping",
+                            ptch / PITCH_STEPS_PER_SEMITONE,
+                            key
+                        );
+                        ptch = ptch.clamp(-MAX_PITCH, MAX_PITCH);
                     }
-                    pitch[key as usize - 1] = ptch as i16 * 512;
+                    pitch[key as usize - 1] = ptch.round() as i16;
 
                     if p + 1 < pitches.len() {
                         p += 1;
                     }
                 }
             }
+            _ => return Err("Cannot pitch a synth patch".to_string()),
         }
         Ok(())
     }
 
-    pub fn gain(&mut self, keys: &[u8], gains: &[f32]) -> Result<(), String> {
-        let mut g = 0;
-        if gains.is_empty() {
+    /// Set the volume of the given keys. When `relative` is set, the volumes are added to the
+    /// current volume of each key.
+    pub fn volume(
+        &mut self,
+        keys: &[u8],
+        volumes: &[Volume],
+        relative: bool,
+    ) -> Result<(), String> {
+        let mut v = 0;
+        if volumes.is_empty() {
             return Err("No gains provided".to_string());
         }
 
         match self {
-            Self::Sampler { .. } => return Err("Cannot gain a synth sample".to_string()),
             Self::Drum { volume, .. } => {
                 for &key in keys.iter() {
-                    if key < 1 || key > 24 {
+                    if !(1..=24).contains(&key) {
                         return Err(format!("Key {} out of range (1-24)", key));
                     }
-                    let gain = gains[g];
-                    if gain < -1.0 || gain > 1.0 {
-                        return Err(format!("Gain {} out of range (-1-+1)", gain));
+                    let current = volume[key as usize - 1] as f32;
+                    let mut vol = match (volumes[v], relative) {
+                        (Volume::Raw(raw), false) => raw,
+                        (Volume::Raw(raw), true) => current + raw,
+                        (Volume::Db(db), false) => db_to_volume(db),
+                        (Volume::Db(db), true) => db_to_volume(volume_to_db(current) + db),
+                    };
+                    if !(0.0..=MAX_VOLUME).contains(&vol) {
+                        log::warn!(
+                            "Volume {} on key {} out of range (0-{}), clamping",
+                            vol,
+                            key,
+                            MAX_VOLUME
+                        );
+                        vol = vol.clamp(0.0, MAX_VOLUME);
                     }
-                    volume[key as usize - 1] = (8192.0 * (gain + 1.0)) as u16;
+                    volume[key as usize - 1] = vol.round() as u16;
 
-                    if g + 1 < gains.len() {
-                        g += 1;
+                    if v + 1 < volumes.len() {
+                        v += 1;
                     }
                 }
             }
+            _ => return Err("Cannot gain a synth patch".to_string()),
         }
         Ok(())
     }
 
     pub fn reverse(&mut self, keys: &[u8], rev: bool) -> Result<(), String> {
         match self {
-            Self::Sampler { .. } => return Err("Cannot reverse a synth sample".to_string()),
             Self::Drum { reverse, .. } => {
                 for &key in keys.iter() {
-                    if key < 1 || key > 24 {
+                    if !(1..=24).contains(&key) {
                         return Err(format!("Key {} out of range (1-24)", key));
                     }
                     reverse[key as usize - 1] = if rev { 16384 } else { 8192 };
                 }
             }
+            _ => return Err("Cannot reverse a synth patch".to_string()),
+        }
+        Ok(())
+    }
+
+    pub fn playmode(&mut self, keys: &[u8], mode: PlayMode) -> Result<(), String> {
+        match self {
+            Self::Drum { playmode, .. } => {
+                for &key in keys.iter() {
+                    if !(1..=24).contains(&key) {
+                        return Err(format!("Key {} out of range (1-24)", key));
+                    }
+                    playmode[key as usize - 1] = mode;
+                }
+            }
+            _ => return Err("Cannot set the playmode of a synth patch".to_string()),
+        }
+        Ok(())
+    }
+
+    /// The data shared by every synth engine other than the sampler.
+    pub fn synth(&self) -> Option<&SynthData> {
+        match self {
+            Self::Cluster(synth)
+            | Self::Digital(synth)
+            | Self::Dimension(synth)
+            | Self::DNA(synth)
+            | Self::DSynth(synth)
+            | Self::FM(synth)
+            | Self::Phase(synth)
+            | Self::Pulse(synth)
+            | Self::String(synth)
+            | Self::Voltage(synth) => Some(synth),
+            Self::Drum { .. } | Self::Sampler { .. } => None,
+        }
+    }
+
+    pub fn synth_mut(&mut self) -> Option<&mut SynthData> {
+        match self {
+            Self::Cluster(synth)
+            | Self::Digital(synth)
+            | Self::Dimension(synth)
+            | Self::DNA(synth)
+            | Self::DSynth(synth)
+            | Self::FM(synth)
+            | Self::Phase(synth)
+            | Self::Pulse(synth)
+            | Self::String(synth)
+            | Self::Voltage(synth) => Some(synth),
+            Self::Drum { .. } | Self::Sampler { .. } => None,
+        }
+    }
+
+    /// Whether the effect is on, its type, and its parameters.
+    pub fn fx(&self) -> (&bool, &FXType, &[u16; 8]) {
+        match self {
+            Self::Drum {
+                fx_active,
+                fx_type,
+                fx_params,
+                ..
+            }
+            | Self::Sampler {
+                fx_active,
+                fx_type,
+                fx_params,
+                ..
+            } => (fx_active, fx_type, fx_params),
+            _ => {
+                let synth = self.synth().unwrap();
+                (&synth.fx_active, &synth.fx_type, &synth.fx_params)
+            }
+        }
+    }
+
+    fn fx_mut(&mut self) -> (&mut bool, &mut FXType, &mut [u16; 8]) {
+        match self {
+            Self::Drum {
+                fx_active,
+                fx_type,
+                fx_params,
+                ..
+            }
+            | Self::Sampler {
+                fx_active,
+                fx_type,
+                fx_params,
+                ..
+            } => (fx_active, fx_type, fx_params),
+            _ => {
+                let synth = self.synth_mut().unwrap();
+                (
+                    &mut synth.fx_active,
+                    &mut synth.fx_type,
+                    &mut synth.fx_params,
+                )
+            }
+        }
+    }
+
+    /// Whether the LFO is on, its type, and its parameters.
+    pub fn lfo(&self) -> (&bool, &LFOType, &[u16; 8]) {
+        match self {
+            Self::Drum {
+                lfo_active,
+                lfo_type,
+                lfo_params,
+                ..
+            }
+            | Self::Sampler {
+                lfo_active,
+                lfo_type,
+                lfo_params,
+                ..
+            } => (lfo_active, lfo_type, lfo_params),
+            _ => {
+                let synth = self.synth().unwrap();
+                (&synth.lfo_active, &synth.lfo_type, &synth.lfo_params)
+            }
+        }
+    }
+
+    fn lfo_mut(&mut self) -> (&mut bool, &mut LFOType, &mut [u16; 8]) {
+        match self {
+            Self::Drum {
+                lfo_active,
+                lfo_type,
+                lfo_params,
+                ..
+            }
+            | Self::Sampler {
+                lfo_active,
+                lfo_type,
+                lfo_params,
+                ..
+            } => (lfo_active, lfo_type, lfo_params),
+            _ => {
+                let synth = self.synth_mut().unwrap();
+                (
+                    &mut synth.lfo_active,
+                    &mut synth.lfo_type,
+                    &mut synth.lfo_params,
+                )
+            }
+        }
+    }
+
+    pub fn set_fx_param(&mut self, name: &str, value: u16) -> Result<(), String> {
+        let i = self.fx_param_index(name)?;
+        self.fx_mut().2[i] = value;
+        Ok(())
+    }
+
+    fn fx_param_index(&self, name: &str) -> Result<usize, String> {
+        let (_, fx_type, _) = self.fx();
+        fx_type.param_index(name).ok_or_else(|| {
+            format!(
+                "Unknown parameter {} for FX {} (expected one of: {})",
+                name,
+                fx_type,
+                fx_type.param_names().join(", ")
+            )
+        })
+    }
+
+    pub fn set_lfo_param(&mut self, name: &str, value: u16) -> Result<(), String> {
+        let i = self.lfo_param_index(name)?;
+        self.lfo_mut().2[i] = value;
+        Ok(())
+    }
+
+    fn lfo_param_index(&self, name: &str) -> Result<usize, String> {
+        let (_, lfo_type, _) = self.lfo();
+        lfo_type.param_index(name).ok_or_else(|| {
+            format!(
+                "Unknown parameter {} for LFO {} (expected one of: {})",
+                name,
+                lfo_type,
+                lfo_type.param_names().join(", ")
+            )
+        })
+    }
+
+    /// Turn the effect on, optionally changing its type, and set the given parameters from
+    /// normalised 0-1 values. Changing the type resets the parameters to their defaults first.
+    /// If `active` is false, the effect is turned off instead.
+    pub fn set_fx(
+        &mut self,
+        fx: Option<FXType>,
+        active: bool,
+        params: &[(String, f32)],
+    ) -> Result<(), String> {
+        let (fx_active, fx_type, fx_params) = self.fx_mut();
+        *fx_active = active;
+        if let Some(fx) = fx {
+            if fx != *fx_type {
+                *fx_type = fx;
+                *fx_params = DEFAULT_FX_PARAMS;
+            }
+        }
+        for (name, value) in params.iter() {
+            self.set_fx_param(name, normalised_param(name, *value)?)?;
+        }
+        Ok(())
+    }
+
+    /// Turn the LFO on, optionally changing its type, and set the given parameters from
+    /// normalised 0-1 values. Changing the type resets the parameters to their defaults first.
+    /// If `active` is false, the LFO is turned off instead.
+    pub fn set_lfo(
+        &mut self,
+        lfo: Option<LFOType>,
+        active: bool,
+        params: &[(String, f32)],
+    ) -> Result<(), String> {
+        let (lfo_active, lfo_type, lfo_params) = self.lfo_mut();
+        *lfo_active = active;
+        if let Some(lfo) = lfo {
+            if lfo != *lfo_type {
+                *lfo_type = lfo;
+                *lfo_params = DEFAULT_LFO_PARAMS;
+            }
+        }
+        for (name, value) in params.iter() {
+            self.set_lfo_param(name, normalised_param(name, *value)?)?;
+        }
+        Ok(())
+    }
+
+    /// The envelope of a synth patch, starting with the stages in `ENVELOPE_NAMES`.
+    pub fn adsr(&self) -> Option<&[u16; 8]> {
+        match self {
+            Self::Drum { .. } => None,
+            Self::Sampler { adsr, .. } => Some(adsr),
+            _ => Some(&self.synth().unwrap().adsr),
+        }
+    }
+
+    fn adsr_mut(&mut self) -> Option<&mut [u16; 8]> {
+        match self {
+            Self::Drum { .. } => None,
+            Self::Sampler { adsr, .. } => Some(adsr),
+            _ => Some(&mut self.synth_mut().unwrap().adsr),
+        }
+    }
+
+    /// Set envelope stages, named as in `ENVELOPE_NAMES`, to raw values. See `ms_to_envelope`
+    /// for the attack, decay and release times.
+    pub fn set_envelope(&mut self, stages: &[(&str, u16)]) -> Result<(), String> {
+        let adsr = match self.adsr_mut() {
+            Some(adsr) => adsr,
+            None => return Err("Cannot set the envelope of a drum patch".to_string()),
+        };
+        for (name, value) in stages.iter() {
+            match ENVELOPE_NAMES.iter().position(|n| n == name) {
+                Some(i) => adsr[i] = *value,
+                None => return Err(format!("Unknown envelope stage {}", name)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Set sampler knobs, named as in `SAMPLER_KNOB_NAMES`, from normalised 0-1 values.
+    pub fn set_knobs(&mut self, params: &[(String, f32)]) -> Result<(), String> {
+        match self {
+            Self::Sampler { knobs, .. } => {
+                for (name, value) in params.iter() {
+                    match SAMPLER_KNOB_NAMES.iter().position(|n| n == name) {
+                        Some(i) => knobs[i] = normalised_param(name, *value)?,
+                        None => {
+                            return Err(format!(
+                                "Unknown knob {} (expected one of: {})",
+                                name,
+                                SAMPLER_KNOB_NAMES.join(", ")
+                            ))
+                        }
+                    }
+                }
+            }
+            _ => return Err("Can only set the knobs of a sampler patch".to_string()),
+        }
+        Ok(())
+    }
+
+    /// Set the sampler's loop from positions given as fractions of the sample length. These are
+    /// scaled to lie between the start and end knobs.
+    pub fn set_loop(&mut self, loop_start: f32, loop_end: f32) -> Result<(), String> {
+        match self {
+            Self::Sampler { knobs, .. } => {
+                let (start, end) = (knobs[0] as f32, knobs[3] as f32);
+                knobs[1] = (start + loop_start * (end - start)).round() as u16;
+                knobs[2] = (start + loop_end * (end - start)).round() as u16;
+            }
+            _ => return Err("Can only set the loop of a sampler patch".to_string()),
         }
         Ok(())
     }
@@ -201,7 +787,6 @@ impl OP1Data {
         }
 
         match self {
-            Self::Sampler { .. } => return Err("Cannot copy a synth sample".to_string()),
             Self::Drum {
                 start,
                 end,
@@ -212,11 +797,11 @@ impl OP1Data {
                 ..
             } => {
                 for &key in keys.iter() {
-                    if key < 1 || key > 24 {
+                    if !(1..=24).contains(&key) {
                         return Err(format!("Key {} out of range (1-24)", key));
                     }
                     let src = srcs[s];
-                    if src < 1 || src > 24 {
+                    if !(1..=24).contains(&src) {
                         return Err(format!("Key {} out of range (1-24)", src));
                     }
 
@@ -232,6 +817,7 @@ impl OP1Data {
                     }
                 }
             }
+            _ => return Err("Cannot copy a synth patch".to_string()),
         }
         Ok(())
     }
@@ -239,22 +825,464 @@ impl OP1Data {
 
 impl Default for OP1Data {
     fn default() -> Self {
-        Self::default_drum()
+        Self::default_drum(Device::OP1)
+    }
+}
+
+/// How a drum key plays its sample. Stored as 0/8192/16384; any other stored value is kept as
+/// `Other`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(from = "u16", into = "u16")]
+pub enum PlayMode {
+    Gate,
+    OneShot,
+    Loop,
+    Other(u16),
+}
+
+impl From<u16> for PlayMode {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => Self::Gate,
+            8192 => Self::OneShot,
+            16384 => Self::Loop,
+            _ => Self::Other(value),
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-// #[serde(untagged)]
+impl From<PlayMode> for u16 {
+    fn from(mode: PlayMode) -> Self {
+        match mode {
+            PlayMode::Gate => 0,
+            PlayMode::OneShot => 8192,
+            PlayMode::Loop => 16384,
+            PlayMode::Other(value) => value,
+        }
+    }
+}
+
+impl fmt::Display for PlayMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Gate => f.write_str("gate"),
+            Self::OneShot => f.write_str("oneshot"),
+            Self::Loop => f.write_str("loop"),
+            Self::Other(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl str::FromStr for PlayMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gate" => Ok(Self::Gate),
+            "oneshot" => Ok(Self::OneShot),
+            "loop" => Ok(Self::Loop),
+            _ => Err(format!("Invalid playmode: {}", s)),
+        }
+    }
+}
+
+/// The effect of a patch. Any effect this tool doesn't know about is kept as `Other`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+#[allow(clippy::upper_case_acronyms)]
 pub enum FXType {
     Delay,
-    // Other(String),
+    Spring,
+    Punch,
+    Grid,
+    Nitro,
+    Phone,
+    CWO,
+    Other(String),
+}
+
+impl FXType {
+    /// The names of the parameters stored in `fx_params`, in order. The first four correspond
+    /// to the blue, green, white and orange encoders; the other four aren't shown on the device
+    /// and are named by their position.
+    pub fn param_names(&self) -> &'static [&'static str] {
+        match self {
+            Self::Delay => &[
+                "rate", "feedback", "filter", "mix", "param5", "param6", "param7", "param8",
+            ],
+            Self::Spring => &[
+                "length", "damping", "tone", "mix", "param5", "param6", "param7", "param8",
+            ],
+            Self::Punch => &[
+                "punch", "release", "tone", "mix", "param5", "param6", "param7", "param8",
+            ],
+            Self::Grid => &[
+                "rate", "feedback", "colour", "mix", "param5", "param6", "param7", "param8",
+            ],
+            Self::Nitro => &[
+                "cutoff",
+                "resonance",
+                "envelope",
+                "mix",
+                "param5",
+                "param6",
+                "param7",
+                "param8",
+            ],
+            Self::Phone => &[
+                "tone",
+                "distortion",
+                "resolution",
+                "mix",
+                "param5",
+                "param6",
+                "param7",
+                "param8",
+            ],
+            Self::CWO => &[
+                "rate", "depth", "feedback", "mix", "param5", "param6", "param7", "param8",
+            ],
+            Self::Other(_) => &[],
+        }
+    }
+
+    pub fn param_index(&self, name: &str) -> Option<usize> {
+        self.param_names().iter().position(|&n| n == name)
+    }
+}
+
+impl From<String> for FXType {
+    fn from(value: String) -> Self {
+        value.parse().unwrap_or(Self::Other(value))
+    }
+}
+
+impl From<FXType> for String {
+    fn from(fx: FXType) -> Self {
+        fx.to_string()
+    }
+}
+
+impl fmt::Display for FXType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Delay => "delay",
+            Self::Spring => "spring",
+            Self::Punch => "punch",
+            Self::Grid => "grid",
+            Self::Nitro => "nitro",
+            Self::Phone => "phone",
+            Self::CWO => "cwo",
+            Self::Other(value) => value,
+        })
+    }
+}
+
+impl str::FromStr for FXType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delay" => Ok(Self::Delay),
+            "spring" => Ok(Self::Spring),
+            "punch" => Ok(Self::Punch),
+            "grid" => Ok(Self::Grid),
+            "nitro" => Ok(Self::Nitro),
+            "phone" => Ok(Self::Phone),
+            "cwo" => Ok(Self::CWO),
+            _ => Err(format!("Invalid FX type: {}", s)),
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-// #[serde(untagged)]
+/// The LFO of a patch. Any LFO this tool doesn't know about is kept as `Other`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+#[allow(clippy::upper_case_acronyms)]
 pub enum LFOType {
     Tremolo,
-    // Other(String),
+    Bend,
+    Crank,
+    Element,
+    MIDI,
+    Random,
+    Value,
+    Other(String),
+}
+
+impl LFOType {
+    /// The names of the parameters stored in `lfo_params`, in order. The first four correspond
+    /// to the blue, green, white and orange encoders; the other four aren't shown on the device
+    /// and are named by their position.
+    pub fn param_names(&self) -> &'static [&'static str] {
+        match self {
+            Self::Tremolo => &[
+                "rate", "pitch", "amount", "shape", "param5", "param6", "param7", "param8",
+            ],
+            Self::Bend => &[
+                "amount",
+                "destination",
+                "parameter",
+                "envelope",
+                "param5",
+                "param6",
+                "param7",
+                "param8",
+            ],
+            Self::Crank => &[
+                "speed",
+                "amount",
+                "destination",
+                "parameter",
+                "param5",
+                "param6",
+                "param7",
+                "param8",
+            ],
+            Self::Element => &[
+                "source",
+                "amount",
+                "destination",
+                "parameter",
+                "param5",
+                "param6",
+                "param7",
+                "param8",
+            ],
+            Self::MIDI => &[
+                "source",
+                "amount",
+                "destination",
+                "parameter",
+                "param5",
+                "param6",
+                "param7",
+                "param8",
+            ],
+            Self::Random => &[
+                "rate",
+                "amount",
+                "destination",
+                "parameter",
+                "param5",
+                "param6",
+                "param7",
+                "param8",
+            ],
+            Self::Value => &[
+                "value",
+                "amount",
+                "destination",
+                "parameter",
+                "param5",
+                "param6",
+                "param7",
+                "param8",
+            ],
+            Self::Other(_) => &[],
+        }
+    }
+
+    pub fn param_index(&self, name: &str) -> Option<usize> {
+        self.param_names().iter().position(|&n| n == name)
+    }
+}
+
+impl From<String> for LFOType {
+    fn from(value: String) -> Self {
+        value.parse().unwrap_or(Self::Other(value))
+    }
+}
+
+impl From<LFOType> for String {
+    fn from(lfo: LFOType) -> Self {
+        lfo.to_string()
+    }
+}
+
+impl fmt::Display for LFOType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tremolo => "tremolo",
+            Self::Bend => "bend",
+            Self::Crank => "crank",
+            Self::Element => "element",
+            Self::MIDI => "midi",
+            Self::Random => "random",
+            Self::Value => "value",
+            Self::Other(value) => value,
+        })
+    }
+}
+
+impl str::FromStr for LFOType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tremolo" => Ok(Self::Tremolo),
+            "bend" => Ok(Self::Bend),
+            "crank" => Ok(Self::Crank),
+            "element" => Ok(Self::Element),
+            "midi" => Ok(Self::MIDI),
+            "random" => Ok(Self::Random),
+            "value" => Ok(Self::Value),
+            _ => Err(format!("Invalid LFO type: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drum_pitch(data: &OP1Data, key: u8) -> i16 {
+        match data {
+            OP1Data::Drum { pitch, .. } => pitch[key as usize - 1],
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn names_are_checked_when_set_or_converted() {
+        let mut data = OP1Data::default_drum(Device::OP1);
+        assert!(data.set_name(Device::OP1, "").is_err());
+        assert!(data.set_name(Device::OP1, "caf\u{e9}").is_err());
+        assert!(data
+            .set_name(Device::OPZ, "a name of twenty chars")
+            .is_err());
+        data.set_name(Device::OP1, "a name of twenty chars")
+            .unwrap();
+        assert!(data.set_device(Device::OPZ).is_err());
+
+        // Edits leave existing names alone
+        if let OP1Data::Drum { name, .. } = &mut data {
+            *name = String::new();
+        }
+        data.pitch(&[1], &[2.0], false).unwrap();
+        assert_eq!(data.name(), "");
+    }
+
+    #[test]
+    fn pretty_json_keeps_the_key_order() {
+        let data = OP1Data::default_drum(Device::OP1);
+        let json = data.to_json(&[]);
+        let key_order: Vec<String> = json.keys().rev().cloned().collect();
+        let bytes = data.to_bytes(&key_order);
+        let end = bytes.iter().position(|&x| x == 0).unwrap_or(bytes.len());
+
+        // As `dump` and then `set` do
+        let (data, key_order) = OP1Data::from_bytes(&bytes[..end]).unwrap();
+        let pretty = serde_json::to_vec_pretty(&data.to_json(&key_order)).unwrap();
+        let (data, key_order) = OP1Data::from_bytes(&pretty).unwrap();
+        assert_eq!(data.to_bytes(&key_order), bytes);
+    }
+
+    #[test]
+    fn pitch_is_stored_in_steps() {
+        let mut data = OP1Data::default_drum(Device::OP1);
+        data.pitch(&[1, 2, 3], &[2.0, -0.35, 12.5], false).unwrap();
+        assert_eq!(drum_pitch(&data, 1), 1024);
+        assert_eq!(drum_pitch(&data, 2), -179);
+        assert_eq!(drum_pitch(&data, 3), 6400);
+    }
+
+    #[test]
+    fn pitch_is_clamped() {
+        let mut data = OP1Data::default_drum(Device::OP1);
+        data.pitch(&[1, 2], &[60.0, -48.5], false).unwrap();
+        assert_eq!(drum_pitch(&data, 1), 24576);
+        assert_eq!(drum_pitch(&data, 2), -24576);
+    }
+
+    #[test]
+    fn relative_pitch_is_clamped() {
+        let mut data = OP1Data::default_drum(Device::OP1);
+        data.pitch(&[1], &[40.0], false).unwrap();
+        data.pitch(&[1], &[10.0], true).unwrap();
+        assert_eq!(drum_pitch(&data, 1), 24576);
+        data.pitch(&[1], &[-50.0], true).unwrap();
+        assert_eq!(drum_pitch(&data, 1), -1024);
+    }
+
+    #[test]
+    fn last_pitch_fills_remaining_keys() {
+        let mut data = OP1Data::default_drum(Device::OP1);
+        data.pitch(&[1, 2, 3], &[1.0, 2.0], false).unwrap();
+        assert_eq!(drum_pitch(&data, 3), 1024);
+    }
+
+    fn drum_volume(data: &OP1Data, key: u8) -> u16 {
+        match data {
+            OP1Data::Drum { volume, .. } => volume[key as usize - 1],
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn volume_db_conversions() {
+        assert_eq!(volume_to_db(8192.0), 0.0);
+        assert!((volume_to_db(16384.0) - 12.04).abs() < 0.01);
+        assert_eq!(volume_to_db(0.0), f32::NEG_INFINITY);
+        assert_eq!(db_to_volume(0.0), 8192.0);
+        assert_eq!(db_to_volume(f32::NEG_INFINITY), 0.0);
+    }
+
+    #[test]
+    fn volume_db_round_trip() {
+        for raw in [1.0, 100.0, 4096.0, 8192.0, 12000.0, 16384.0] {
+            let back = db_to_volume(volume_to_db(raw));
+            assert!((back - raw).abs() < raw * 1e-4, "{} -> {}", raw, back);
+        }
+        for db in [-60.0, -6.0, 0.0, 3.0, 12.0] {
+            let back = volume_to_db(db_to_volume(db));
+            assert!((back - db).abs() < 1e-3, "{} -> {}", db, back);
+        }
+    }
+
+    #[test]
+    fn volume_sets_keys() {
+        let mut data = OP1Data::default_drum(Device::OP1);
+        let volumes = [
+            Volume::Db(0.0),
+            Volume::Db(f32::NEG_INFINITY),
+            Volume::Raw(0.0),
+        ];
+        data.volume(&[1, 2, 3], &volumes, false).unwrap();
+        assert_eq!(drum_volume(&data, 1), 8192);
+        assert_eq!(drum_volume(&data, 2), 0);
+        assert_eq!(drum_volume(&data, 3), 0);
+    }
+
+    #[test]
+    fn relative_volume() {
+        let mut data = OP1Data::default_drum(Device::OP1);
+        data.volume(&[1, 2], &[Volume::Db(6.0), Volume::Raw(100.0)], true)
+            .unwrap();
+        assert_eq!(drum_volume(&data, 1), db_to_volume(6.0).round() as u16);
+        assert_eq!(drum_volume(&data, 2), 8292);
+        data.volume(&[1], &[Volume::Db(-6.0)], true).unwrap();
+        assert!((drum_volume(&data, 1) as i32 - 8192).abs() <= 1);
+    }
+
+    #[test]
+    fn relative_volume_of_silent_key_stays_silent() {
+        let mut data = OP1Data::default_drum(Device::OP1);
+        data.volume(&[1], &[Volume::Raw(0.0)], false).unwrap();
+        data.volume(&[1], &[Volume::Db(6.0)], true).unwrap();
+        assert_eq!(drum_volume(&data, 1), 0);
+        data.volume(&[1], &[Volume::Raw(50.0)], true).unwrap();
+        assert_eq!(drum_volume(&data, 1), 50);
+    }
+
+    #[test]
+    fn volume_is_clamped() {
+        let mut data = OP1Data::default_drum(Device::OP1);
+        data.volume(&[1, 2], &[Volume::Db(20.0), Volume::Raw(20000.0)], false)
+            .unwrap();
+        assert_eq!(drum_volume(&data, 1), 16384);
+        assert_eq!(drum_volume(&data, 2), 16384);
+        data.volume(&[1], &[Volume::Raw(-20000.0)], true).unwrap();
+        assert_eq!(drum_volume(&data, 1), 0);
+    }
 }
@@ -0,0 +1,151 @@
+// Parsing the values given to the editing subcommands and in kit manifests.
+
+use crate::op1;
+
+/// A pitch in semitones, from semitones (optionally suffixed with `st`) or cents (`c`).
+pub fn parse_pitch(pitch: &str) -> Result<f32, String> {
+    let invalid = || format!("Invalid pitch: {}", pitch);
+    let semitones = if let Some(cents) = pitch.strip_suffix('c') {
+        cents.parse::<f32>().map_err(|_| invalid())? / 100.0
+    } else {
+        let semitones = pitch.strip_suffix("st").unwrap_or(pitch);
+        semitones.parse::<f32>().map_err(|_| invalid())?
+    };
+    if !semitones.is_finite() {
+        return Err(invalid());
+    }
+    Ok(semitones)
+}
+
+/// A pitch as with `parse_pitch`, or a range of pitches `a:b` that steps by a semitone from `a`
+/// towards `b`, e.g. `0:3` or `-50c:150c`.
+pub fn parse_pitches(pitches: &str) -> Result<Vec<f32>, String> {
+    let range: Vec<&str> = pitches.split(':').collect();
+    match range.len() {
+        1 => Ok(vec![parse_pitch(pitches)?]),
+        2 => {
+            let start = parse_pitch(range[0])?;
+            let end = parse_pitch(range[1])?;
+            // Longer ranges would only reach pitches that are clamped
+            if (end - start).abs() > 2.0 * op1::MAX_PITCH / op1::PITCH_STEPS_PER_SEMITONE {
+                return Err(format!("Pitch range {} is too long", pitches));
+            }
+            let steps = (end - start).abs().floor() as usize;
+            let step = if start <= end { 1.0 } else { -1.0 };
+            Ok((0..=steps).map(|i| start + i as f32 * step).collect())
+        }
+        _ => Err(format!("Invalid pitch: {}", pitches)),
+    }
+}
+
+/// A volume in dB (suffixed with `dB`, or `-inf`), as a raw value with `raw`, or else as a gain
+/// from -1 to +1.
+pub fn parse_volume(volume: &str, raw: bool) -> Result<op1::Volume, String> {
+    let invalid = || format!("Invalid volume: {}", volume);
+    let lower = volume.to_lowercase();
+    let db = lower.strip_suffix("db").unwrap_or(&lower);
+    if db == "-inf" {
+        return Ok(op1::Volume::Db(f32::NEG_INFINITY));
+    }
+    let value = db.parse::<f32>().map_err(|_| invalid())?;
+    if !value.is_finite() {
+        return Err(invalid());
+    }
+    Ok(if db.len() < lower.len() {
+        op1::Volume::Db(value)
+    } else if raw {
+        op1::Volume::Raw(value)
+    } else {
+        if !(-1.0..=1.0).contains(&value) {
+            return Err(format!("Gain {} out of range (-1-+1)", value));
+        }
+        op1::Volume::Db(op1::volume_to_db(8192.0 * (value + 1.0)))
+    })
+}
+
+/// A time in milliseconds, from milliseconds (`ms`) or seconds (`s`).
+pub fn parse_time(time: &str) -> Result<f32, String> {
+    let invalid = || format!("Invalid time: {}", time);
+    if let Some(ms) = time.strip_suffix("ms") {
+        ms.parse::<f32>().map_err(|_| invalid())
+    } else if let Some(s) = time.strip_suffix('s') {
+        Ok(s.parse::<f32>().map_err(|_| invalid())? * 1000.0)
+    } else {
+        Err(invalid())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pitch_units() {
+        assert_eq!(parse_pitch("2"), Ok(2.0));
+        assert_eq!(parse_pitch("-2"), Ok(-2.0));
+        assert_eq!(parse_pitch("+12.5st"), Ok(12.5));
+        assert_eq!(parse_pitch("-35c"), Ok(-0.35));
+        assert_eq!(parse_pitch("150c"), Ok(1.5));
+    }
+
+    #[test]
+    fn invalid_pitches() {
+        assert!(parse_pitch("").is_err());
+        assert!(parse_pitch("st").is_err());
+        assert!(parse_pitch("2cents").is_err());
+        assert!(parse_pitch("2 st").is_err());
+        assert!(parse_pitch("NaN").is_err());
+        assert!(parse_pitch("inf").is_err());
+        assert!(parse_pitch("-infst").is_err());
+        assert!(parse_pitch("1e40c").is_err());
+    }
+
+    #[test]
+    fn pitch_ranges() {
+        assert_eq!(parse_pitches("0:3"), Ok(vec![0.0, 1.0, 2.0, 3.0]));
+        assert_eq!(parse_pitches("3:0"), Ok(vec![3.0, 2.0, 1.0, 0.0]));
+        assert_eq!(parse_pitches("-1st:1st"), Ok(vec![-1.0, 0.0, 1.0]));
+        assert_eq!(parse_pitches("-50c:150c"), Ok(vec![-0.5, 0.5, 1.5]));
+        assert_eq!(parse_pitches("0:2.5"), Ok(vec![0.0, 1.0, 2.0]));
+        assert_eq!(parse_pitches("2:2"), Ok(vec![2.0]));
+        assert!(parse_pitches("-48:48").is_ok());
+        assert!(parse_pitches("0:1e30").is_err());
+        assert!(parse_pitches("0:x").is_err());
+        assert!(parse_pitches("0:1:2").is_err());
+    }
+
+    #[test]
+    fn volume_units() {
+        use op1::Volume::{Db, Raw};
+        assert_eq!(parse_volume("+3dB", false), Ok(Db(3.0)));
+        assert_eq!(parse_volume("-6db", true), Ok(Db(-6.0)));
+        assert_eq!(parse_volume("-inf", false), Ok(Db(f32::NEG_INFINITY)));
+        assert_eq!(parse_volume("-INFdB", true), Ok(Db(f32::NEG_INFINITY)));
+        assert_eq!(parse_volume("8192", true), Ok(Raw(8192.0)));
+        assert_eq!(parse_volume("0", true), Ok(Raw(0.0)));
+        assert_eq!(parse_volume("-100", true), Ok(Raw(-100.0)));
+    }
+
+    #[test]
+    fn volume_gains() {
+        use op1::Volume::Db;
+        assert_eq!(parse_volume("0", false), Ok(Db(0.0)));
+        assert_eq!(parse_volume("-1", false), Ok(Db(f32::NEG_INFINITY)));
+        assert_eq!(
+            parse_volume("1", false),
+            Ok(Db(op1::volume_to_db(op1::MAX_VOLUME)))
+        );
+        assert!(parse_volume("1.5", false).is_err());
+        assert!(parse_volume("-1.01", false).is_err());
+    }
+
+    #[test]
+    fn invalid_volumes() {
+        assert!(parse_volume("", false).is_err());
+        assert!(parse_volume("dB", false).is_err());
+        assert!(parse_volume("loud", true).is_err());
+        assert!(parse_volume("NaN", true).is_err());
+        assert!(parse_volume("infdB", false).is_err());
+        assert!(parse_volume("inf", true).is_err());
+    }
+}
@@ -0,0 +1,641 @@
+// Converting WAV and AIFF audio into patch sound data, and building patches from it.
+
+use crate::audio;
+use crate::chunks::{self, read_aif};
+use crate::keys;
+use crate::op1;
+use crate::wav_meta;
+use std::error;
+use std::fs;
+use std::io;
+
+fn wav_data_to_i16(data: &wav::BitDepth) -> Vec<i16> {
+    match data {
+        wav::BitDepth::Eight(d) => d.iter().map(|&x| (x as i16) << 8).collect(),
+        wav::BitDepth::Sixteen(d) => d.to_vec(),
+        wav::BitDepth::TwentyFour(d) => d.iter().map(|&x| (x >> 16) as i16).collect(),
+        wav::BitDepth::ThirtyTwoFloat(d) => d.iter().map(|&x| (x as i32 >> 16) as i16).collect(),
+        wav::BitDepth::Empty => vec![],
+    }
+}
+
+pub fn wav_i16_to_bytes(data: &[i16]) -> Vec<u8> {
+    let mut r = Vec::with_capacity(data.len() * 2);
+    for x in data.iter() {
+        let [a, b] = x.to_be_bytes();
+        r.push(a);
+        r.push(b);
+    }
+    r
+}
+
+/// Keep the first channel of interleaved audio. `num_channels` must be at least 1.
+fn drop_channels(data: &[i16], num_channels: usize) -> Vec<i16> {
+    data.iter().step_by(num_channels).copied().collect()
+}
+
+fn halve_bitrate(data: &[i16]) -> Vec<i16> {
+    let mut r = Vec::with_capacity(data.len() / 2);
+    let mut i = 0;
+    while i < data.len() {
+        r.push(data[i]);
+        i += 2;
+    }
+    r
+}
+
+fn wav_to_samples(header: &wav::Header, data: &wav::BitDepth) -> Result<Vec<i16>, &'static str> {
+    if header.sampling_rate != 44100 {
+        Err("Sample must be encoded at 44100 Hz")?;
+    }
+    if header.audio_format != 1 {
+        Err("Sample must be PCM encoded")?;
+    }
+    if header.channel_count < 1 {
+        Err("Sample has no channels")?;
+    }
+
+    let mut data = wav_data_to_i16(data);
+
+    if header.channel_count != 1 {
+        data = drop_channels(&data, header.channel_count as usize);
+    }
+    Ok(data)
+}
+
+fn aif_to_samples(form: &chunks::FormChunk) -> Result<Vec<i16>, &'static str> {
+    if form.common.sample_rate_hz() != 44100.0 {
+        Err("Sample must be encoded at 44100 Hz")?;
+    }
+    if form.common.num_channels < 1 {
+        Err("Sample has no channels")?;
+    }
+    let sound = form.sound.as_ref().ok_or("AIFF file has no sound data")?;
+    let data = sound
+        .sound_data
+        .get(sound.offset as usize..)
+        .ok_or("AIFF sound data offset out of range")?;
+
+    let width = (form.common.bit_rate as usize).div_ceil(8);
+    if !(1..=4).contains(&width) {
+        Err("Unsupported AIFF bit depth")?;
+    }
+    // Keep the most significant 16 bits of each big-endian sample
+    let mut data: Vec<i16> = data
+        .chunks_exact(width)
+        .map(|b| match width {
+            1 => (b[0] as i8 as i16) << 8,
+            _ => i16::from_be_bytes([b[0], b[1]]),
+        })
+        .collect();
+
+    if form.common.num_channels != 1 {
+        data = drop_channels(&data, form.common.num_channels as usize);
+    }
+
+    Ok(data)
+}
+
+/// A new patch with the given metadata and 16 bit mono sound data. It has no INST or MARK
+/// chunk: those only belong in sampler patches, which add them.
+pub fn patch_form(op_data: op1::OP1Data, sound_data: Vec<u8>) -> chunks::FormChunk {
+    let mut form = chunks::FormChunk::default();
+    form.common.num_sample_frames = sound_data.len() as u32 / 2;
+    form.app.push(chunks::ApplicationSpecificChunk::OP1 {
+        data: op_data,
+        key_order: vec![],
+    });
+    form.sound = Some(chunks::SoundDataChunk {
+        size: sound_data.len() as i32 + 8,
+        offset: 0,
+        block_size: 0,
+        sound_data,
+    });
+    form
+}
+
+/// The audio of a WAV or AIFF file, along with the root note and loop stored in its metadata.
+pub struct Sample {
+    pub samples: Vec<i16>,
+    pub root_note: Option<f32>,
+    // Start and (exclusive) end, in samples
+    pub sample_loop: Option<(usize, usize)>,
+}
+
+/// Read a WAV or AIFF file, converting its audio to 16 bit mono.
+pub fn read_sample(buffer: Vec<u8>) -> Result<Sample, Box<dyn error::Error>> {
+    if buffer.starts_with(chunks::FORM) {
+        let form = read_aif(&mut buffer.as_slice())?;
+        log::info!("AIFF source: {:#?}", form);
+        let instrument = form.instrument.as_ref();
+        return Ok(Sample {
+            samples: aif_to_samples(&form)?,
+            root_note: instrument.map(|inst| inst.root_note()),
+            sample_loop: instrument
+                .and_then(|inst| inst.sustain_loop.positions(form.markers.as_ref()))
+                .map(|(start, end)| (start as usize, end as usize)),
+        });
+    }
+
+    let (header, data) = wav::read(&mut io::Cursor::new(&buffer))?;
+    let meta = wav_meta::WavMetadata::read(&buffer);
+    log::info!("WAV header: {:#?}", header);
+    log::info!("WAV metadata: {:#?}", meta);
+    Ok(Sample {
+        samples: wav_to_samples(&header, &data)?,
+        root_note: meta.root_note(),
+        sample_loop: meta
+            .sample_loop()
+            .map(|l| (l.start as usize, l.end as usize + 1)),
+    })
+}
+
+/// Read the WAV and AIFF files in `dir`, sorted by their root notes. Files whose note cannot
+/// be found are skipped.
+pub fn read_multisample(dir: &str) -> Result<Vec<(String, Sample)>, Box<dyn error::Error>> {
+    let mut paths: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| ["wav", "aif", "aiff"].contains(&ext.to_lowercase().as_str()))
+                .unwrap_or(false)
+        })
+        .collect();
+    paths.sort();
+
+    let mut sources = vec![];
+    for path in paths {
+        let name = path.to_string_lossy().to_string();
+        let mut source = read_sample(fs::read(&path)?)?;
+        let root_note = source
+            .root_note
+            .or_else(|| audio::note_from_filename(&name).map(|note| note as f32))
+            .or_else(|| audio::detect_pitch(&source.samples, 44100).map(audio::freq_to_midi_note));
+        match root_note {
+            Some(note) => {
+                log::info!("{}: note {:.2}", name, note);
+                source.root_note = Some(note);
+                sources.push((name, source));
+            }
+            None => log::warn!("Could not find the note of {}. Skipping.", name),
+        }
+    }
+    if sources.is_empty() {
+        Err(format!("No note files found in {}", dir))?;
+    }
+    sources.sort_by(|a, b| a.1.root_note.partial_cmp(&b.1.root_note).unwrap());
+    Ok(sources)
+}
+
+/// The source that needs the least pitching to cover `range`.
+pub fn best_multisample(sources: Vec<(String, Sample)>, range: (f32, f32)) -> Sample {
+    let middle = (range.0 + range.1) / 2.0;
+    let distance = |source: &Sample| (source.root_note.unwrap() - middle).abs();
+    let (name, source) = sources
+        .into_iter()
+        .min_by(|a, b| distance(&a.1).partial_cmp(&distance(&b.1)).unwrap())
+        .unwrap();
+    log::info!("Using {} as the source", name);
+    source
+}
+
+/// For every key, the index of the note in `notes` that is closest to the note the key plays.
+/// Keys between two notes go to the nearest one, and ties go to the first.
+fn closest_notes(notes: &[f32]) -> Vec<usize> {
+    (1..=keys::NUM_KEYS)
+        .map(|key| {
+            let note = keys::midi_note(key) as f32;
+            let distance = |i: &usize| (notes[*i] - note).abs();
+            (0..notes.len())
+                .min_by(|a, b| distance(a).partial_cmp(&distance(b)).unwrap())
+                .unwrap()
+        })
+        .collect()
+}
+
+/// Create a drum patch that maps every key to the note file closest to it, pitched to the note
+/// of the key.
+pub fn multisample_drum_form(
+    sources: Vec<(String, Sample)>,
+    device: op1::Device,
+    name: &str,
+) -> Result<chunks::FormChunk, Box<dyn error::Error>> {
+    let notes: Vec<f32> = sources
+        .iter()
+        .map(|(_, source)| source.root_note.unwrap())
+        .collect();
+    let closest = closest_notes(&notes);
+    let mut used: Vec<usize> = closest.clone();
+    used.sort_unstable();
+    used.dedup();
+
+    let max_len = 44100 * device.max_drum_seconds() / used.len(); // Hz * seconds / samples
+    let mut sound_data: Vec<u8> = vec![];
+    let mut starts: [u32; 24] = [0; 24];
+    let mut ends: [u32; 24] = [0; 24];
+    let mut pitches = [0.0; 24];
+    for &i in used.iter() {
+        let (name, source) = &sources[i];
+        let mut samples = source.samples.clone();
+        if samples.len() > max_len {
+            log::warn!(
+                "{} is longer than {:.2} seconds. Truncating to fit.",
+                name,
+                max_len as f32 / 44100.0
+            );
+            samples.truncate(max_len);
+        }
+        let start = sound_data.len() as u32 * op1::POSITION_PER_BYTE;
+        sound_data.extend(wav_i16_to_bytes(&samples));
+        let end = sound_data.len() as u32 * op1::POSITION_PER_BYTE;
+        for (key, _) in closest.iter().enumerate().filter(|(_, &c)| c == i) {
+            starts[key] = start;
+            ends[key] = end;
+            pitches[key] = keys::midi_note(key as u8 + 1) as f32 - notes[i];
+        }
+    }
+
+    let mut op_data = op1::OP1Data::default_drum(device);
+    op_data.set_name(device, name)?;
+    if let op1::OP1Data::Drum {
+        ref mut start,
+        ref mut end,
+        ..
+    } = op_data
+    {
+        *start = starts;
+        *end = ends;
+    }
+    let all_keys: Vec<u8> = (1..=keys::NUM_KEYS).collect();
+    op_data.pitch(&all_keys, &pitches, true)?;
+    Ok(patch_form(op_data, sound_data))
+}
+
+/// Create a sampler patch. When `find_loop` is set, the loop stored in the source is used, or
+/// else one is found.
+pub fn synth_form(
+    mut samples: Vec<i16>,
+    stored_loop: Option<(usize, usize)>,
+    basefreq: u16,
+    find_loop: bool,
+    device: op1::Device,
+    name: &str,
+) -> Result<chunks::FormChunk, Box<dyn error::Error>> {
+    let target_len = 44100 * device.max_synth_seconds(); // Hz * seconds
+    if samples.len() > target_len {
+        log::warn!(
+            "Sample is longer than {} seconds. Truncating to fit.",
+            device.max_synth_seconds()
+        );
+        samples.truncate(target_len);
+    }
+
+    let sample_loop = if find_loop {
+        let stored = stored_loop.filter(|&(start, end)| start < end && end <= samples.len());
+        match stored {
+            Some(l) => {
+                log::info!("Using loop from source metadata: {:?}", l);
+                Some(l)
+            }
+            None => {
+                let found = audio::find_loop(&samples, 44100);
+                match found {
+                    Some((start, end)) => {
+                        log::info!("Found loop: {:?}", (start, end));
+                        audio::crossfade_loop(&mut samples, start, end);
+                    }
+                    None => log::warn!("Could not find a loop in the sample."),
+                }
+                found
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut op_data = op1::OP1Data::default_sampler();
+    op_data.set_name(device, name)?;
+    if let op1::OP1Data::Sampler {
+        ref mut base_freq, ..
+    } = op_data
+    {
+        *base_freq = basefreq;
+    }
+    let mut instrument = chunks::InstrumentChunk::default();
+    instrument.set_root_note(audio::freq_to_midi_note(basefreq as f32));
+    let mut markers = None;
+    if let Some((start, end)) = sample_loop {
+        let len = samples.len() as f32;
+        op_data.set_loop(start as f32 / len, end as f32 / len)?;
+        instrument.sustain_loop = chunks::Loop {
+            play_mode: 1,
+            begin_loop: 1,
+            end_loop: 2,
+        };
+        markers = Some(chunks::MarkerChunk::new(vec![
+            chunks::Marker {
+                id: 1,
+                position: start as u32,
+                marker_name: "beg loop".to_string(),
+            },
+            chunks::Marker {
+                id: 2,
+                position: end as u32,
+                marker_name: "end loop".to_string(),
+            },
+        ]));
+    }
+
+    let mut form = patch_form(op_data, wav_i16_to_bytes(&samples));
+    form.instrument = Some(instrument);
+    form.markers = markers;
+    Ok(form)
+}
+
+/// How `drum_form` lays out its input files.
+pub struct DrumOptions {
+    pub shift: usize,
+    pub octave: u8,
+    pub copy_remaining: bool,
+    pub pitch_shift_remaining: bool,
+    pub low_res: bool,
+    pub use_root_notes: bool,
+}
+
+impl Default for DrumOptions {
+    fn default() -> Self {
+        Self {
+            shift: 0,
+            octave: 5,
+            copy_remaining: false,
+            pitch_shift_remaining: false,
+            low_res: false,
+            use_root_notes: false,
+        }
+    }
+}
+
+/// Create a drum patch, with the input files mapped to the keys in order.
+pub fn drum_form(
+    input_files: &[&str],
+    options: &DrumOptions,
+    device: op1::Device,
+    name: &str,
+) -> Result<chunks::FormChunk, Box<dyn error::Error>> {
+    let DrumOptions {
+        shift,
+        octave: octav,
+        copy_remaining,
+        pitch_shift_remaining,
+        low_res,
+        use_root_notes,
+    } = *options;
+    if !(1..=10).contains(&octav) {
+        Err("OCTAVE must be between 1 and 10")?;
+    }
+    if input_files.is_empty() {
+        Err("No input files")?;
+    }
+    if shift + input_files.len() > 24 {
+        Err("Shift value N plus number of input files must not exceed 24 keys")?;
+    }
+
+    let mut sound_data: Vec<u8> = vec![];
+    let mut starts: [u32; 24] = [0; 24];
+    let mut ends: [u32; 24] = [0; 24];
+    let mut pitches: [i16; 24] = [if low_res { 12 * -512 } else { 0 }; 24];
+    // Pitch offsets, in semitones, from the root notes of the samples
+    let mut offsets: [Option<f32>; 24] = [None; 24];
+    let max_len = 44100 * device.max_drum_seconds() * 2; // Hz * seconds * 2 bytes
+    for (i, input) in (shift..).zip(input_files.iter()) {
+        let sample = read_sample(fs::read(input)?)?;
+        if use_root_notes {
+            match sample.root_note {
+                Some(note) => offsets[i] = Some(keys::midi_note(i as u8 + 1) as f32 - note),
+                None => log::warn!("{} has no root note", input),
+            }
+        }
+        let mut samples = sample.samples;
+        if low_res {
+            samples = halve_bitrate(&samples);
+        }
+        let data = wav_i16_to_bytes(&samples);
+        if sound_data.len() + data.len() > max_len {
+            Err(format!(
+                "Samples cannot add up to more than {} seconds",
+                device.max_drum_seconds()
+            ))?;
+        }
+        starts[i] = sound_data.len() as u32 * op1::POSITION_PER_BYTE;
+        sound_data.extend(&data);
+        ends[i] = sound_data.len() as u32 * op1::POSITION_PER_BYTE;
+    }
+
+    if pitch_shift_remaining || copy_remaining {
+        let last = shift + input_files.len() - 1;
+        for i in 0..shift {
+            starts[i] = starts[shift];
+            ends[i] = ends[shift];
+            offsets[i] = offsets[shift];
+        }
+        for i in last..24 {
+            starts[i] = starts[last];
+            ends[i] = ends[last];
+            offsets[i] = offsets[last];
+        }
+
+        if pitch_shift_remaining {
+            for (i, p) in pitches.iter_mut().enumerate().take(shift) {
+                *p += (shift - i) as i16 * -512;
+            }
+            for (i, p) in pitches.iter_mut().enumerate().skip(last) {
+                *p += (i - last) as i16 * 512;
+            }
+        }
+    }
+
+    let mut op_data = op1::OP1Data::default_drum(device);
+    op_data.set_name(device, name)?;
+    if let op1::OP1Data::Drum {
+        ref mut octave,
+        ref mut start,
+        ref mut end,
+        ref mut pitch,
+        ..
+    } = op_data
+    {
+        *octave = octav as i8 - 5;
+        *start = starts;
+        *end = ends;
+        *pitch = pitches;
+    }
+    let (offset_keys, offsets): (Vec<u8>, Vec<f32>) = (1..=24)
+        .zip(offsets.iter())
+        .filter_map(|(key, offset)| Some((key, (*offset)?)))
+        .unzip();
+    if !offset_keys.is_empty() {
+        op_data.pitch(&offset_keys, &offsets, true)?;
+    }
+
+    Ok(patch_form(op_data, sound_data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_note_covers_every_key() {
+        assert_eq!(closest_notes(&[60.0]), vec![0; 24]);
+    }
+
+    #[test]
+    fn keys_go_to_the_nearest_note() {
+        // Notes at G3 (key 3) and A#4 (key 18)
+        let closest = closest_notes(&[55.0, 70.0]);
+        assert_eq!(closest[0], 0); // F3, below the lowest note
+        assert_eq!(closest[9], 0); // D4, 7 semitones above G3 and 8 below A#4
+        assert_eq!(closest[10], 1); // D#4, 8 semitones above G3 and 7 below A#4
+        assert_eq!(closest[23], 1); // E5, above the highest note
+    }
+
+    #[test]
+    fn ties_go_to_the_first_note() {
+        // A3 (key 5) is 2 semitones from both G3 and B3
+        let closest = closest_notes(&[55.0, 59.0]);
+        assert_eq!(closest[4], 0);
+    }
+
+    #[test]
+    fn notes_need_not_be_sorted() {
+        let closest = closest_notes(&[72.0, 48.0, 60.0]);
+        assert_eq!(&closest[0..2], &[1, 1]); // F3 and F#3 go to C3
+        assert_eq!(&closest[2..13], &[2; 11]); // G3 to F4 go to C4
+        assert_eq!(&closest[13..], &[0; 11]); // F#4 to E5 go to C5
+    }
+
+    #[test]
+    fn fractional_notes() {
+        let closest = closest_notes(&[53.4, 53.6]);
+        assert_eq!(closest[0], 0);
+        assert_eq!(closest[1], 1);
+    }
+
+    fn aif_form(num_channels: i16, sound_data: Vec<u8>) -> chunks::FormChunk {
+        let mut form = patch_form(op1::OP1Data::default_sampler(), sound_data);
+        form.common.num_channels = num_channels;
+        form
+    }
+
+    #[test]
+    fn drop_channels_keeps_the_first() {
+        assert_eq!(drop_channels(&[1, 2, 3, 4, 5, 6], 2), vec![1, 3, 5]);
+        assert_eq!(drop_channels(&[1, 2, 3, 4, 5], 3), vec![1, 4]);
+        assert_eq!(drop_channels(&[1, 2, 3], 1), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn aif_channels() {
+        let data = wav_i16_to_bytes(&[1, -1, 2, -2]);
+        assert_eq!(
+            aif_to_samples(&aif_form(1, data.clone())),
+            Ok(vec![1, -1, 2, -2])
+        );
+        assert_eq!(aif_to_samples(&aif_form(2, data.clone())), Ok(vec![1, 2]));
+        assert_eq!(
+            aif_to_samples(&aif_form(0, data.clone())),
+            Err("Sample has no channels")
+        );
+        assert!(aif_to_samples(&aif_form(-2, data)).is_err());
+    }
+
+    #[test]
+    fn wav_channels() {
+        let data = wav::BitDepth::Sixteen(vec![1, -1, 2, -2]);
+        let header = wav::Header::new(1, 2, 44100, 16);
+        assert_eq!(wav_to_samples(&header, &data), Ok(vec![1, 2]));
+        let header = wav::Header::new(1, 0, 44100, 16);
+        assert_eq!(
+            wav_to_samples(&header, &data),
+            Err("Sample has no channels")
+        );
+    }
+
+    /// Write `form` to a file in the temporary directory, returning its path.
+    fn temp_aif(name: &str, form: &chunks::FormChunk) -> String {
+        use chunks::Chunk;
+        let path = std::env::temp_dir().join(format!("{}-{}.aif", name, std::process::id()));
+        let mut bytes = vec![];
+        form.write(&mut bytes).unwrap();
+        fs::write(&path, bytes).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn drum_keys(form: &chunks::FormChunk) -> ([u32; 24], [u32; 24]) {
+        match &form.app[0] {
+            chunks::ApplicationSpecificChunk::OP1 {
+                data: op1::OP1Data::Drum { start, end, .. },
+                ..
+            } => (*start, *end),
+            _ => panic!("Not a drum patch"),
+        }
+    }
+
+    #[test]
+    fn drum_from_aiff() {
+        let stereo = temp_aif("drum-stereo", &aif_form(2, vec![0; 400]));
+        let mono = temp_aif("drum-mono", &aif_form(1, vec![0; 100]));
+        let form = drum_form(
+            &[&stereo, &mono],
+            &Default::default(),
+            op1::Device::OP1,
+            "kit",
+        );
+        fs::remove_file(stereo).unwrap();
+        fs::remove_file(mono).unwrap();
+        let form = form.unwrap();
+        assert_eq!(form.sound.as_ref().unwrap().sound_data.len(), 300);
+        let (start, end) = drum_keys(&form);
+        assert_eq!(start[..2], [0, 200 * op1::POSITION_PER_BYTE]);
+        assert_eq!(
+            end[..2],
+            [200 * op1::POSITION_PER_BYTE, 300 * op1::POSITION_PER_BYTE]
+        );
+    }
+
+    #[test]
+    fn drum_rejects_sources_without_channels() {
+        let path = temp_aif("drum-silent", &aif_form(0, vec![0; 100]));
+        let result = drum_form(&[&path], &Default::default(), op1::Device::OP1, "kit");
+        fs::remove_file(path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn drum_length_is_limited() {
+        let seconds = op1::Device::OP1.max_drum_seconds();
+        let half = 44100 * seconds;
+        let first = temp_aif("drum-long1", &aif_form(1, vec![0; half]));
+        let second = temp_aif("drum-long2", &aif_form(1, vec![0; half + 2]));
+        let fits = drum_form(
+            &[&first, &first],
+            &Default::default(),
+            op1::Device::OP1,
+            "kit",
+        );
+        let too_long = drum_form(
+            &[&first, &second],
+            &Default::default(),
+            op1::Device::OP1,
+            "kit",
+        );
+        fs::remove_file(first).unwrap();
+        fs::remove_file(second).unwrap();
+        assert!(fits.is_ok());
+        assert_eq!(
+            too_long.unwrap_err().to_string(),
+            format!("Samples cannot add up to more than {} seconds", seconds)
+        );
+    }
+}
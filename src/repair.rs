@@ -0,0 +1,517 @@
+// Recovering what can be recovered from a damaged patch, for the `repair` subcommand.
+
+use crate::chunks::{self, parse_chunk, ApplicationSpecificChunk, ChunkHeader, FormChunk};
+use crate::chunks::{
+    AudioRecordingChunk, CommentsChunk, CommonChunk, InstrumentChunk, MIDIDataChunk, MarkerChunk,
+    SoundDataChunk, TextChunk,
+};
+use crate::keys;
+use crate::op1::{self, Device, OP1Data};
+
+/// The value of the top-level `"type"` field in OP metadata, found by scanning for it, so that
+/// it can still be read when the rest of the JSON is damaged.
+fn json_type(json: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(json);
+    let after = &text[text.find("\"type\"")? + 6..];
+    let value = after.trim_start().strip_prefix(':')?.trim_start();
+    let value = value.strip_prefix('"')?;
+    Some(value[..value.find('"')?].to_string())
+}
+
+/// Default metadata to replace OP metadata that can't be decoded. The patch type is taken from
+/// the `"type"` field if it can be read, and is otherwise guessed from the number of sample
+/// frames: only drum patches hold more audio than a sampler patch can.
+fn default_data(json: &[u8], frames: u32) -> OP1Data {
+    let is_drum = match json_type(json) {
+        Some(engine) => engine == "drum",
+        None => frames as usize > 44100 * Device::OP1.max_synth_seconds(),
+    };
+    if is_drum {
+        OP1Data::default_drum(Device::OP1)
+    } else {
+        OP1Data::default_sampler()
+    }
+}
+
+/// A repaired patch, with a description of every fix that was made and of anything that was lost.
+pub struct Repaired {
+    pub form: FormChunk,
+    pub fixes: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// Rebuild the AIFF file in `bytes`, fixing what can be fixed.
+pub fn repair(bytes: &[u8]) -> Result<Repaired, String> {
+    if bytes.len() < 12 || &bytes[0..4] != chunks::FORM {
+        return Err("Not an AIFF file".to_string());
+    }
+    let mut fixes = vec![];
+    let mut warnings = vec![];
+    let form_size = i32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    if form_size as i64 != bytes.len() as i64 - 8 {
+        fixes.push(format!(
+            "FORM size: declared {} bytes, the file holds {}",
+            form_size,
+            bytes.len() - 8
+        ));
+    }
+
+    let mut form = FormChunk::default();
+    let mut common = None;
+    // OP metadata that could not be decoded, replaced once the length of the audio is known
+    let mut undecoded = None;
+    for header in chunks::scan_chunks(bytes) {
+        let ChunkHeader {
+            id,
+            offset,
+            size,
+            available,
+        } = header;
+        let name = String::from_utf8_lossy(&id).to_string();
+        let data = &bytes[offset + 8..offset + 8 + available];
+        let chunk = &bytes[offset..offset + 8 + available];
+        let truncated = size < 0 || available < size as usize;
+
+        match &id {
+            chunks::COMMON => {
+                if available < 18 {
+                    fixes.push(format!(
+                        "COMM: only {} bytes present, replaced with the default format",
+                        available
+                    ));
+                    common = Some(CommonChunk::default());
+                    continue;
+                }
+                if size != 18 {
+                    fixes.push(format!("COMM size: declared {} bytes, should be 18", size));
+                }
+                common = match parse_chunk::<CommonChunk>(chunk) {
+                    Ok(chunk) => Some(chunk),
+                    Err(e) => {
+                        fixes.push(format!(
+                            "COMM: could not be read ({}), replaced with the default format",
+                            e
+                        ));
+                        Some(CommonChunk::default())
+                    }
+                };
+            }
+            chunks::SOUND => {
+                if available < 8 {
+                    fixes.push("SSND: chunk is too short to hold audio, dropped".to_string());
+                    continue;
+                }
+                let sound_data = data[8..].to_vec();
+                if truncated {
+                    fixes.push(format!(
+                        "SSND size: declared {} bytes of audio, only {} present",
+                        size - 8,
+                        sound_data.len()
+                    ));
+                }
+                let word =
+                    |i: usize| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+                form.sound = Some(SoundDataChunk {
+                    size: sound_data.len() as i32 + 8,
+                    offset: word(0),
+                    block_size: word(4),
+                    sound_data,
+                });
+            }
+            chunks::APPLICATION if data.len() >= 4 && &data[0..4] == chunks::OP_1 => {
+                let json = &data[4..];
+                let end = json.iter().position(|&x| x == 0).unwrap_or(json.len());
+                match OP1Data::from_bytes(&json[..end]) {
+                    Ok((data, key_order)) => {
+                        if truncated {
+                            fixes.push(
+                                "APPL: chunk is truncated, but its metadata was read".to_string(),
+                            );
+                        }
+                        form.app
+                            .push(ApplicationSpecificChunk::OP1 { data, key_order })
+                    }
+                    Err(e) => {
+                        undecoded = Some((form.app.len(), json.to_vec(), e));
+                        form.app.push(ApplicationSpecificChunk::OP1 {
+                            data: OP1Data::default_sampler(),
+                            key_order: vec![],
+                        });
+                    }
+                }
+            }
+            _ if truncated => fixes.push(format!(
+                "{}: declared {} bytes, only {} present, dropped",
+                name, size, available
+            )),
+            chunks::APPLICATION => match parse_chunk(chunk) {
+                Ok(chunk) => form.app.push(chunk),
+                Err(e) => fixes.push(format!("APPL: could not be read ({}), dropped", e)),
+            },
+            chunks::MARKER => match parse_chunk::<MarkerChunk>(chunk) {
+                Ok(chunk) => form.markers = Some(chunk),
+                Err(e) => fixes.push(format!("MARK: could not be read ({}), dropped", e)),
+            },
+            chunks::INSTRUMENT => match parse_chunk::<InstrumentChunk>(chunk) {
+                Ok(chunk) => form.instrument = Some(chunk),
+                Err(e) => fixes.push(format!("INST: could not be read ({}), dropped", e)),
+            },
+            chunks::MIDI => match parse_chunk::<MIDIDataChunk>(chunk) {
+                Ok(chunk) => form.midi.push(chunk),
+                Err(e) => fixes.push(format!("MIDI: could not be read ({}), dropped", e)),
+            },
+            chunks::RECORDING => match parse_chunk::<AudioRecordingChunk>(chunk) {
+                Ok(chunk) => form.recording = Some(chunk),
+                Err(e) => fixes.push(format!("AESD: could not be read ({}), dropped", e)),
+            },
+            chunks::COMMENTS => match parse_chunk::<CommentsChunk>(chunk) {
+                Ok(chunk) => form.comments = Some(chunk),
+                Err(e) => fixes.push(format!("COMT: could not be read ({}), dropped", e)),
+            },
+            chunks::NAME | chunks::AUTHOR | chunks::COPYRIGHT | chunks::ANNOTATION => {
+                match parse_chunk::<TextChunk>(chunk) {
+                    Ok(chunk) => form.texts.push(chunk),
+                    Err(e) => fixes.push(format!("{}: could not be read ({}), dropped", name, e)),
+                }
+            }
+            _ => warnings.push(format!(
+                "Dropped the unknown {} chunk at byte {} ({} bytes), it cannot be kept",
+                name, offset, available
+            )),
+        }
+    }
+
+    form.common = match common {
+        Some(common) => common,
+        None => {
+            fixes.push("COMM: missing, added the default format".to_string());
+            CommonChunk::default()
+        }
+    };
+    let default = CommonChunk::default();
+    if form.common.num_channels < 1 {
+        fixes.push(format!(
+            "COMM: {} channels, set to {}",
+            form.common.num_channels, default.num_channels
+        ));
+        form.common.num_channels = default.num_channels;
+    }
+    // With the channels fixed, only the bit depth can make the format invalid
+    if form.common.check_format().is_err() {
+        fixes.push(format!(
+            "COMM: {}-bit samples, set to {}-bit",
+            form.common.bit_rate, default.bit_rate
+        ));
+        form.common.bit_rate = default.bit_rate;
+    }
+
+    let bytes_per_frame = form.common.bytes_per_frame();
+    if let Some(sound) = form.sound.as_mut() {
+        let partial = sound.sound_data.len() % bytes_per_frame;
+        if partial > 0 {
+            let len = sound.sound_data.len() - partial;
+            sound.sound_data.truncate(len);
+            sound.size = len as i32 + 8;
+            fixes.push(format!(
+                "SSND: dropped {} trailing bytes of a partial sample frame",
+                partial
+            ));
+        }
+    }
+    let sound_len = form.sound.as_ref().map_or(0, |s| s.sound_data.len());
+    let frames = (sound_len / bytes_per_frame) as u32;
+    if form.common.num_sample_frames != frames {
+        fixes.push(format!(
+            "COMM: declared {} sample frames, SSND holds {}",
+            form.common.num_sample_frames, frames
+        ));
+        form.common.num_sample_frames = frames;
+    }
+
+    if let Some((i, json, e)) = undecoded {
+        let data = default_data(&json, frames);
+        fixes.push(format!(
+            "APPL: OP metadata could not be decoded ({}), replaced with default {} metadata",
+            e,
+            data.engine()
+        ));
+        form.app[i] = ApplicationSpecificChunk::OP1 {
+            data,
+            key_order: vec![],
+        };
+    }
+
+    if !form
+        .app
+        .iter()
+        .any(|chunk| matches!(chunk, ApplicationSpecificChunk::OP1 { .. }))
+    {
+        let data = default_data(&[], frames);
+        fixes.push(format!(
+            "APPL: missing OP metadata, added default {} metadata",
+            data.engine()
+        ));
+        form.app.insert(
+            0,
+            ApplicationSpecificChunk::OP1 {
+                data,
+                key_order: vec![],
+            },
+        );
+    }
+
+    for chunk in form.app.iter_mut() {
+        if let ApplicationSpecificChunk::OP1 {
+            data: OP1Data::Drum { start, end, .. },
+            ..
+        } = chunk
+        {
+            let max_position =
+                (sound_len as u64 * op1::POSITION_PER_BYTE as u64).min(u32::MAX as u64) as u32;
+            for i in 0..keys::NUM_KEYS as usize {
+                let key = keys::key_name(i as u8 + 1);
+                if end[i] > max_position {
+                    fixes.push(format!(
+                        "Key {}: end {} is beyond the audio, clamped to {}",
+                        key, end[i], max_position
+                    ));
+                    end[i] = max_position;
+                }
+                if start[i] > end[i] {
+                    fixes.push(format!(
+                        "Key {}: start {} is after its end, clamped to {}",
+                        key, start[i], end[i]
+                    ));
+                    start[i] = end[i];
+                }
+            }
+        }
+    }
+
+    Ok(Repaired {
+        form,
+        fixes,
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunks::{read_aif, Chunk};
+    use crate::patch;
+
+    fn write(form: &FormChunk) -> Vec<u8> {
+        let mut bytes = vec![];
+        form.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn drum_patch(sound_len: usize) -> FormChunk {
+        patch::patch_form(OP1Data::default_drum(Device::OP1), vec![0; sound_len])
+    }
+
+    fn chunk_offset(bytes: &[u8], id: &chunks::ChunkID) -> usize {
+        chunks::scan_chunks(bytes)
+            .iter()
+            .find(|h| &h.id == id)
+            .unwrap()
+            .offset
+    }
+
+    /// Repair `bytes`, check that the result can be read back, and return it with the fixes.
+    fn repair_and_read(bytes: &[u8]) -> (FormChunk, Vec<String>) {
+        let repaired = repair(bytes).unwrap();
+        let form = read_aif(&mut &write(&repaired.form)[..]).unwrap();
+        (form, repaired.fixes)
+    }
+
+    #[test]
+    fn intact_patch() {
+        let repaired = repair(&write(&drum_patch(88))).unwrap();
+        assert!(repaired.fixes.is_empty(), "{:?}", repaired.fixes);
+        assert!(repaired.warnings.is_empty());
+    }
+
+    #[test]
+    fn zero_channels() {
+        let mut bytes = write(&drum_patch(88));
+        let comm = chunk_offset(&bytes, chunks::COMMON);
+        bytes[comm + 8..comm + 10].copy_from_slice(&0i16.to_be_bytes());
+        assert!(read_aif(&mut &bytes[..]).is_err());
+        let (form, fixes) = repair_and_read(&bytes);
+        assert_eq!(fixes, ["COMM: 0 channels, set to 1"]);
+        assert_eq!(form.common.num_channels, 1);
+        assert_eq!(form.common.num_sample_frames, 44);
+    }
+
+    #[test]
+    fn invalid_bit_depth() {
+        let mut bytes = write(&drum_patch(88));
+        let comm = chunk_offset(&bytes, chunks::COMMON);
+        bytes[comm + 14..comm + 16].copy_from_slice(&0i16.to_be_bytes());
+        assert!(read_aif(&mut &bytes[..]).is_err());
+        let (form, fixes) = repair_and_read(&bytes);
+        assert_eq!(fixes, ["COMM: 0-bit samples, set to 16-bit"]);
+        assert_eq!(form.common.bit_rate, 16);
+    }
+
+    #[test]
+    fn frames_follow_the_format() {
+        let mut form = drum_patch(120);
+        form.common.num_channels = 2;
+        form.common.bit_rate = 24;
+        let mut bytes = write(&form);
+        let comm = chunk_offset(&bytes, chunks::COMMON);
+        bytes[comm + 10..comm + 14].copy_from_slice(&60u32.to_be_bytes());
+        let (form, fixes) = repair_and_read(&bytes);
+        assert_eq!(fixes, ["COMM: declared 60 sample frames, SSND holds 20"]);
+        assert_eq!(form.common.num_sample_frames, 20);
+    }
+
+    #[test]
+    fn partial_sample_frame() {
+        let mut form = drum_patch(88);
+        form.common.num_channels = 2;
+        let sound = form.sound.as_mut().unwrap();
+        sound.sound_data.truncate(87);
+        sound.size = 87 + 8;
+        form.common.num_sample_frames = 21;
+        let mut bytes = write(&form);
+        bytes.push(0);
+        let form_size = bytes.len() as i32 - 8;
+        bytes[4..8].copy_from_slice(&form_size.to_be_bytes());
+        let (form, fixes) = repair_and_read(&bytes);
+        assert_eq!(
+            fixes,
+            ["SSND: dropped 3 trailing bytes of a partial sample frame"]
+        );
+        assert_eq!(form.sound.unwrap().sound_data.len(), 84);
+    }
+
+    #[test]
+    fn truncated_sound_data() {
+        let mut bytes = write(&drum_patch(88));
+        bytes.truncate(bytes.len() - 10);
+        let (form, fixes) = repair_and_read(&bytes);
+        assert_eq!(
+            fixes,
+            [
+                format!(
+                    "FORM size: declared {} bytes, the file holds {}",
+                    bytes.len() + 2,
+                    bytes.len() - 8
+                ),
+                "SSND size: declared 88 bytes of audio, only 78 present".to_string(),
+                "COMM: declared 44 sample frames, SSND holds 39".to_string(),
+            ]
+        );
+        assert_eq!(form.sound.unwrap().sound_data.len(), 78);
+        assert_eq!(form.common.num_sample_frames, 39);
+    }
+
+    #[test]
+    fn missing_comm() {
+        let mut form = drum_patch(88);
+        form.common.num_sample_frames = 44;
+        let bytes = write(&form);
+        let comm = chunk_offset(&bytes, chunks::COMMON);
+        let mut bytes = [&bytes[..comm], &bytes[comm + 26..]].concat();
+        let form_size = bytes.len() as i32 - 8;
+        bytes[4..8].copy_from_slice(&form_size.to_be_bytes());
+        let (form, fixes) = repair_and_read(&bytes);
+        assert_eq!(
+            fixes,
+            [
+                "COMM: missing, added the default format",
+                "COMM: declared 0 sample frames, SSND holds 44"
+            ]
+        );
+        assert_eq!(form.common.num_channels, 1);
+    }
+
+    #[test]
+    fn undecodable_metadata() {
+        let mut bytes = write(&drum_patch(88));
+        let appl = chunk_offset(&bytes, chunks::APPLICATION);
+        let json = appl + 12;
+        bytes[json] = b'[';
+        let (form, fixes) = repair_and_read(&bytes);
+        assert_eq!(fixes.len(), 1);
+        assert!(fixes[0].ends_with("replaced with default drum metadata"));
+        assert!(matches!(
+            form.app[0],
+            ApplicationSpecificChunk::OP1 {
+                data: OP1Data::Drum { .. },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn missing_metadata() {
+        let mut form = drum_patch(88);
+        form.app.clear();
+        let (form, fixes) = repair_and_read(&write(&form));
+        assert_eq!(
+            fixes,
+            ["APPL: missing OP metadata, added default sampler metadata"]
+        );
+        assert_eq!(form.app.len(), 1);
+    }
+
+    #[test]
+    fn key_regions_beyond_the_audio() {
+        let mut form = drum_patch(88);
+        if let ApplicationSpecificChunk::OP1 {
+            data: OP1Data::Drum { start, end, .. },
+            ..
+        } = &mut form.app[0]
+        {
+            end[0] = u32::MAX;
+            start[1] = 100;
+        }
+        let (form, fixes) = repair_and_read(&write(&form));
+        let max_position = 88 * op1::POSITION_PER_BYTE;
+        assert_eq!(
+            fixes,
+            [
+                format!(
+                    "Key F1: end {} is beyond the audio, clamped to {}",
+                    u32::MAX,
+                    max_position
+                ),
+                "Key F#1: start 100 is after its end, clamped to 0".to_string(),
+            ]
+        );
+        match &form.app[0] {
+            ApplicationSpecificChunk::OP1 {
+                data: OP1Data::Drum { start, end, .. },
+                ..
+            } => {
+                assert_eq!(end[0], max_position);
+                assert_eq!(start[1], 0);
+            }
+            _ => panic!("No drum metadata"),
+        }
+    }
+
+    #[test]
+    fn unknown_chunks_are_dropped() {
+        let mut bytes = write(&drum_patch(88));
+        let offset = bytes.len();
+        bytes.extend_from_slice(b"JUNK\0\0\0\x02ab");
+        let form_size = bytes.len() as i32 - 8;
+        bytes[4..8].copy_from_slice(&form_size.to_be_bytes());
+        let repaired = repair(&bytes).unwrap();
+        assert!(repaired.fixes.is_empty());
+        assert_eq!(
+            repaired.warnings,
+            [format!(
+                "Dropped the unknown JUNK chunk at byte {} (2 bytes), it cannot be kept",
+                offset
+            )]
+        );
+        read_aif(&mut &write(&repaired.form)[..]).unwrap();
+    }
+}
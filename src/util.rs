@@ -1,71 +1,67 @@
-use std::io::Read;
+use crate::chunks::{ChunkError, ChunkID};
+use std::io::{self, Read, Write};
 
-pub fn read_chunk_id(r: &mut impl Read) -> crate::chunks::ChunkID {
-    let mut id = [0; 4];
-    if let Err(e) = r.read_exact(&mut id) {
-        panic!("unable to read_u8 {:?}", e)
-    }
-    id
+fn read_array<const N: usize>(r: &mut impl Read) -> Result<[u8; N], ChunkError> {
+    let mut b = [0; N];
+    r.read_exact(&mut b)?;
+    Ok(b)
 }
 
-pub fn read_u8(r: &mut impl Read) -> u8 {
-    let mut b = [0; 1];
-    if let Err(e) = r.read_exact(&mut b) {
-        panic!("unable to read_u8 {:?}", e)
-    }
-    b[0]
+pub fn read_chunk_id(r: &mut impl Read) -> Result<ChunkID, ChunkError> {
+    read_array(r)
 }
 
-pub fn read_u16_be(r: &mut impl Read) -> u16 {
-    let mut b = [0; 2];
-    if let Err(e) = r.read_exact(&mut b) {
-        panic!("unable to read_u8 {:?}", e)
-    }
-    u16::from_be_bytes(b)
+pub fn read_u8(r: &mut impl Read) -> Result<u8, ChunkError> {
+    Ok(read_array::<1>(r)?[0])
 }
 
-pub fn read_u32_be(r: &mut impl Read) -> u32 {
-    let mut b = [0; 4];
-    if let Err(e) = r.read_exact(&mut b) {
-        panic!("unable to read_i32_be {:?}", e)
-    }
-    u32::from_be_bytes(b)
+pub fn read_u16_be(r: &mut impl Read) -> Result<u16, ChunkError> {
+    Ok(u16::from_be_bytes(read_array(r)?))
 }
 
-pub fn read_i8_be(r: &mut impl Read) -> i8 {
-    let mut b = [0; 1];
-    if let Err(e) = r.read_exact(&mut b) {
-        panic!("unable to read_i32_be {:?}", e)
-    }
-    i8::from_be_bytes(b)
+pub fn read_u32_be(r: &mut impl Read) -> Result<u32, ChunkError> {
+    Ok(u32::from_be_bytes(read_array(r)?))
 }
 
-pub fn read_i16_be(r: &mut impl Read) -> i16 {
-    let mut b = [0; 2];
-    if let Err(e) = r.read_exact(&mut b) {
-        panic!("unable to read_i32_be {:?}", e)
-    }
-    i16::from_be_bytes(b)
+pub fn read_i8_be(r: &mut impl Read) -> Result<i8, ChunkError> {
+    Ok(i8::from_be_bytes(read_array(r)?))
 }
 
-pub fn read_i32_be(r: &mut impl Read) -> i32 {
-    let mut b = [0; 4];
-    if let Err(e) = r.read_exact(&mut b) {
-        panic!("unable to read_i32_be {:?}", e)
-    }
-    i32::from_be_bytes(b)
+pub fn read_i16_be(r: &mut impl Read) -> Result<i16, ChunkError> {
+    Ok(i16::from_be_bytes(read_array(r)?))
 }
 
-pub fn read_pstring(r: &mut impl Read) -> String {
-    let len = read_u8(r);
-    let mut str_buf = vec![0; len as usize];
-    r.read_exact(&mut str_buf).unwrap();
+pub fn read_i32_be(r: &mut impl Read) -> Result<i32, ChunkError> {
+    Ok(i32::from_be_bytes(read_array(r)?))
+}
+
+/// Read `len` bytes of text.
+pub fn read_string(r: &mut impl Read, len: usize) -> Result<String, ChunkError> {
+    let mut str_buf = vec![0; len];
+    r.read_exact(&mut str_buf)?;
+    String::from_utf8(str_buf).map_err(|e| ChunkError::InvalidData(e.to_string()))
+}
+
+pub fn read_pstring(r: &mut impl Read) -> Result<String, ChunkError> {
+    let len = read_u8(r)?;
+    let s = read_string(r, len as usize)?;
 
-    if len % 2 > 0 {
-        // skip pad byte if odd
-        let mut skip = [0; 1];
-        r.read_exact(&mut skip).unwrap()
+    if len.is_multiple_of(2) {
+        // skip pad byte, so that the count and text have an even length
+        read_u8(r)?;
     }
 
-    String::from_utf8(str_buf).unwrap()
+    Ok(s)
+}
+
+/// Write a Pascal-style string, padded so that the count and text have an even length.
+pub fn write_pstring(w: &mut impl Write, s: &str) -> Result<usize, io::Error> {
+    let bytes = &s.as_bytes()[..s.len().min(255)];
+    w.write_all(&[bytes.len() as u8])?;
+    w.write_all(bytes)?;
+    if bytes.len().is_multiple_of(2) {
+        w.write_all(&[0])?;
+        return Ok(bytes.len() + 2);
+    }
+    Ok(bytes.len() + 1)
 }
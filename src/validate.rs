@@ -0,0 +1,464 @@
+// Checking that a patch will load on the device, for the `validate` subcommand.
+
+use crate::chunks::{self, ApplicationSpecificChunk, Chunk, CommonChunk, FormChunk};
+use crate::keys;
+use crate::op1::{self, Device, FXType, LFOType, OP1Data, PlayMode};
+use std::fmt;
+use std::io::Cursor;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Severity {
+    /// The patch may not behave as expected.
+    Warning,
+    /// The patch is unlikely to load.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Warning => "warning",
+            Self::Error => "error",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn error(&mut self, message: String) {
+        self.0.push(Finding {
+            severity: Severity::Error,
+            message,
+        });
+    }
+
+    fn warning(&mut self, message: String) {
+        self.0.push(Finding {
+            severity: Severity::Warning,
+            message,
+        });
+    }
+}
+
+/// Check the AIFF file in `bytes` against the constraints of `device`: the sizes of its chunks,
+/// its audio format and length, and the values of its OP metadata.
+pub fn validate(bytes: &[u8], device: Device) -> Vec<Finding> {
+    let mut findings = Findings(vec![]);
+    if !check_structure(bytes, &mut findings) {
+        return findings.0;
+    }
+    match FormChunk::parse(&mut Cursor::new(bytes.to_vec())) {
+        Ok(form) => check_form(&form, device, declared_frames(bytes), &mut findings),
+        Err(e) => findings.error(format!("Could not read the patch: {}", e)),
+    }
+    findings.0
+}
+
+/// Check the FORM and chunk sizes. Returns whether the chunks are intact enough to be parsed.
+fn check_structure(bytes: &[u8], findings: &mut Findings) -> bool {
+    if bytes.len() < 12 || &bytes[0..4] != chunks::FORM {
+        findings.error("Not an AIFF file".to_string());
+        return false;
+    }
+    let form_size = i32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    let actual = bytes.len() as i64 - 8;
+    if form_size as i64 != actual {
+        findings.error(format!(
+            "FORM declares {} bytes, but the file holds {}",
+            form_size, actual
+        ));
+    }
+    if &bytes[8..12] != chunks::AIFF && &bytes[8..12] != chunks::AIFF_C {
+        findings.error(format!(
+            "Unknown FORM type {:?}",
+            String::from_utf8_lossy(&bytes[8..12])
+        ));
+        return false;
+    }
+
+    let mut intact = true;
+    let headers = chunks::scan_chunks(bytes);
+    for header in headers.iter() {
+        let id = String::from_utf8_lossy(&header.id);
+        if header.size < 0 || header.available < header.size as usize {
+            findings.error(format!(
+                "{} chunk at offset {} declares {} bytes, but only {} are present",
+                id, header.offset, header.size, header.available
+            ));
+            intact = false;
+        } else if &header.id == chunks::COMMON {
+            let chunk = &bytes[header.offset..header.offset + 8 + header.available];
+            let format = chunks::parse_chunk::<CommonChunk>(chunk)
+                .map_err(|e| format!("COMM: {}", e))
+                .and_then(|common| common.check_format());
+            if let Err(e) = format {
+                findings.error(e);
+                intact = false;
+            }
+        }
+        if &header.id == chunks::SOUND && header.size < 8 {
+            findings.error(format!(
+                "SSND chunk declares {} bytes, less than its 8 byte header",
+                header.size
+            ));
+            intact = false;
+        }
+    }
+    if let Some(last) = headers.last() {
+        let end = last.offset + 8 + last.available;
+        if last.size % 2 == 1 && last.available == last.size as usize && end == bytes.len() {
+            intact = false;
+            findings.error(format!(
+                "{} chunk at offset {} has an odd size, but no pad byte",
+                String::from_utf8_lossy(&last.id),
+                last.offset
+            ));
+        }
+    }
+    for id in [chunks::COMMON, chunks::SOUND, chunks::APPLICATION] {
+        if !headers.iter().any(|h| &h.id == id) {
+            findings.error(format!("No {} chunk", String::from_utf8_lossy(id)));
+        }
+    }
+    intact
+}
+
+/// The number of sample frames declared by the COMM chunk. `FormChunk::parse` replaces this with
+/// the number of frames in SSND, so it is read from the file instead.
+fn declared_frames(bytes: &[u8]) -> Option<u32> {
+    let header = chunks::scan_chunks(bytes)
+        .into_iter()
+        .find(|h| &h.id == chunks::COMMON)?;
+    if header.available < 6 {
+        return None;
+    }
+    let i = header.offset + 8 + 2; // after num_channels
+    Some(u32::from_be_bytes([
+        bytes[i],
+        bytes[i + 1],
+        bytes[i + 2],
+        bytes[i + 3],
+    ]))
+}
+
+fn check_form(
+    form: &FormChunk,
+    device: Device,
+    declared_frames: Option<u32>,
+    findings: &mut Findings,
+) {
+    let common = &form.common;
+    let sample_rate = common.sample_rate_hz();
+    if sample_rate != 44100.0 {
+        findings.error(format!("Sample rate is {} Hz, not 44100 Hz", sample_rate));
+    }
+    if common.num_channels != 1 {
+        findings.error(format!("{} channels, not mono", common.num_channels));
+    }
+    if common.bit_rate != 16 {
+        findings.error(format!("{}-bit samples, not 16-bit", common.bit_rate));
+    }
+    let sound_len = form.sound.as_ref().map_or(0, |s| s.sound_data.len());
+    let bytes_per_frame = common.bytes_per_frame();
+    let frames = sound_len / bytes_per_frame;
+    if !sound_len.is_multiple_of(bytes_per_frame) {
+        findings.warning(format!(
+            "SSND holds {} bytes, not a whole number of {}-byte sample frames",
+            sound_len, bytes_per_frame
+        ));
+    }
+    if let Some(declared) = declared_frames.filter(|&d| d as usize != frames) {
+        findings.warning(format!(
+            "COMM declares {} sample frames, but SSND holds {}",
+            declared, frames
+        ));
+    }
+
+    let data = match form.app.first() {
+        Some(ApplicationSpecificChunk::OP1 { data, .. }) => data,
+        _ => {
+            findings.error("No OP metadata".to_string());
+            return;
+        }
+    };
+    check_data(data, device, sound_len, frames, findings);
+}
+
+fn check_data(
+    data: &OP1Data,
+    device: Device,
+    sound_len: usize,
+    frames: usize,
+    findings: &mut Findings,
+) {
+    if let Err(e) = device.check_name(data.name()) {
+        findings.error(e);
+    }
+
+    let seconds = frames as f32 / 44100.0;
+    let max_seconds = match data {
+        OP1Data::Drum { .. } => device.max_drum_seconds(),
+        _ => device.max_synth_seconds(),
+    };
+    if seconds > max_seconds as f32 {
+        findings.error(format!(
+            "Audio is {:.2}s long, more than the {}s a {} patch can hold",
+            seconds,
+            max_seconds,
+            data.engine()
+        ));
+    }
+
+    if let FXType::Other(name) = data.fx().1 {
+        findings.warning(format!("Unknown FX type {}", name));
+    }
+    if let LFOType::Other(name) = data.lfo().1 {
+        findings.warning(format!("Unknown LFO type {}", name));
+    }
+
+    match data {
+        OP1Data::Drum {
+            start,
+            end,
+            pitch,
+            reverse,
+            volume,
+            playmode,
+            ..
+        } => {
+            let max_position = sound_len as u64 * op1::POSITION_PER_BYTE as u64;
+            for i in 0..keys::NUM_KEYS as usize {
+                let key = keys::key_name(i as u8 + 1);
+                if start[i] > end[i] {
+                    findings.error(format!(
+                        "Key {} starts at {}, after its end at {}",
+                        key, start[i], end[i]
+                    ));
+                }
+                if end[i] as u64 > max_position {
+                    findings.error(format!(
+                        "Key {} ends at {}, beyond the end of the audio at {}",
+                        key, end[i], max_position
+                    ));
+                }
+                if (pitch[i] as f32).abs() > op1::MAX_PITCH {
+                    findings.error(format!(
+                        "Key {} pitch {} out of range (±{})",
+                        key,
+                        pitch[i],
+                        op1::MAX_PITCH
+                    ));
+                }
+                if volume[i] as f32 > op1::MAX_VOLUME {
+                    findings.error(format!(
+                        "Key {} volume {} out of range (0-{})",
+                        key,
+                        volume[i],
+                        op1::MAX_VOLUME
+                    ));
+                }
+                if reverse[i] != 8192 && reverse[i] != 16384 {
+                    findings.warning(format!(
+                        "Key {} direction {} is neither forward (8192) nor reverse (16384)",
+                        key, reverse[i]
+                    ));
+                }
+                if let PlayMode::Other(value) = playmode[i] {
+                    findings.warning(format!("Key {} has an unknown playmode {}", key, value));
+                }
+            }
+        }
+        OP1Data::Sampler {
+            base_freq, knobs, ..
+        } => {
+            if *base_freq == 0 {
+                findings.error("Base frequency is 0 Hz".to_string());
+            }
+            if knobs[0] > knobs[3] {
+                findings.warning(format!(
+                    "Sampler starts at {}, after its end at {}",
+                    knobs[0], knobs[3]
+                ));
+            }
+            if knobs[1] > knobs[2] {
+                findings.warning(format!(
+                    "Sampler loop starts at {}, after its end at {}",
+                    knobs[1], knobs[2]
+                ));
+            }
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch;
+
+    fn write(form: &FormChunk) -> Vec<u8> {
+        let mut bytes = vec![];
+        form.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn drum_patch() -> FormChunk {
+        patch::patch_form(OP1Data::default_drum(Device::OP1), vec![0; 88])
+    }
+
+    fn chunk_offset(bytes: &[u8], id: &chunks::ChunkID) -> usize {
+        chunks::scan_chunks(bytes)
+            .iter()
+            .find(|h| &h.id == id)
+            .unwrap()
+            .offset
+    }
+
+    fn set_form_size(bytes: &mut [u8]) {
+        let size = bytes.len() as i32 - 8;
+        bytes[4..8].copy_from_slice(&size.to_be_bytes());
+    }
+
+    fn messages(findings: &[Finding], severity: Severity) -> Vec<&str> {
+        findings
+            .iter()
+            .filter(|f| f.severity == severity)
+            .map(|f| f.message.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn valid_patch() {
+        assert!(validate(&write(&drum_patch()), Device::OP1).is_empty());
+    }
+
+    #[test]
+    fn zero_channels() {
+        let mut bytes = write(&drum_patch());
+        let comm = chunk_offset(&bytes, chunks::COMMON);
+        bytes[comm + 8..comm + 10].copy_from_slice(&0i16.to_be_bytes());
+        let findings = validate(&bytes, Device::OP1);
+        assert_eq!(messages(&findings, Severity::Error), ["COMM: 0 channels"]);
+    }
+
+    #[test]
+    fn zero_bit_samples() {
+        let mut bytes = write(&drum_patch());
+        let comm = chunk_offset(&bytes, chunks::COMMON);
+        bytes[comm + 14..comm + 16].copy_from_slice(&0i16.to_be_bytes());
+        let findings = validate(&bytes, Device::OP1);
+        assert_eq!(
+            messages(&findings, Severity::Error),
+            ["COMM: 0-bit samples"]
+        );
+    }
+
+    #[test]
+    fn short_comm_chunk() {
+        let mut bytes = write(&drum_patch());
+        let comm = chunk_offset(&bytes, chunks::COMMON);
+        bytes[comm + 4..comm + 8].copy_from_slice(&6i32.to_be_bytes());
+        let findings = validate(&bytes, Device::OP1);
+        assert!(messages(&findings, Severity::Error).contains(&"COMM: Chunk data ends early"));
+    }
+
+    #[test]
+    fn truncated_sound_data() {
+        let mut bytes = write(&drum_patch());
+        let ssnd = chunk_offset(&bytes, chunks::SOUND);
+        bytes.truncate(bytes.len() - 10);
+        let findings = validate(&bytes, Device::OP1);
+        let errors = messages(&findings, Severity::Error);
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].starts_with("FORM declares"));
+        assert_eq!(
+            errors[1],
+            format!(
+                "SSND chunk at offset {} declares 96 bytes, but only 86 are present",
+                ssnd
+            )
+        );
+    }
+
+    #[test]
+    fn sound_chunk_shorter_than_its_header() {
+        let mut form = drum_patch();
+        form.sound.as_mut().unwrap().size = 4;
+        form.sound.as_mut().unwrap().sound_data.clear();
+        let mut bytes = write(&form);
+        bytes.truncate(bytes.len() - 4);
+        set_form_size(&mut bytes);
+        let findings = validate(&bytes, Device::OP1);
+        assert_eq!(
+            messages(&findings, Severity::Error),
+            ["SSND chunk declares 4 bytes, less than its 8 byte header"]
+        );
+    }
+
+    #[test]
+    fn odd_sized_chunks() {
+        let mut bytes = write(&drum_patch());
+        bytes.extend_from_slice(b"NAME\0\0\0\x03abc\0");
+        set_form_size(&mut bytes);
+        assert!(validate(&bytes, Device::OP1).is_empty());
+
+        bytes.pop();
+        set_form_size(&mut bytes);
+        let findings = validate(&bytes, Device::OP1);
+        assert_eq!(
+            messages(&findings, Severity::Error),
+            [format!(
+                "NAME chunk at offset {} has an odd size, but no pad byte",
+                bytes.len() - 11
+            )]
+        );
+    }
+
+    #[test]
+    fn partial_sample_frame() {
+        let mut form = drum_patch();
+        let sound = form.sound.as_mut().unwrap();
+        sound.sound_data.truncate(87);
+        sound.size = 87 + 8;
+        let mut bytes = write(&form);
+        bytes.push(0);
+        set_form_size(&mut bytes);
+        let findings = validate(&bytes, Device::OP1);
+        assert!(messages(&findings, Severity::Warning)
+            .contains(&"SSND holds 87 bytes, not a whole number of 2-byte sample frames"));
+    }
+
+    #[test]
+    fn missing_chunks() {
+        let mut form = drum_patch();
+        form.sound = None;
+        let findings = validate(&write(&form), Device::OP1);
+        assert_eq!(messages(&findings, Severity::Error), ["No SSND chunk"]);
+
+        form.app.clear();
+        let findings = validate(&write(&form), Device::OP1);
+        assert_eq!(
+            messages(&findings, Severity::Error),
+            ["No SSND chunk", "No APPL chunk", "No OP metadata"]
+        );
+    }
+
+    #[test]
+    fn not_aiff() {
+        let findings = validate(b"RIFF\0\0\0\x04WAVE", Device::OP1);
+        assert_eq!(messages(&findings, Severity::Error), ["Not an AIFF file"]);
+    }
+}
@@ -0,0 +1,234 @@
+// Sampler metadata stored in the chunks of a WAV file, which the `wav` crate does not expose.
+
+use std::convert::TryInto;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampleLoop {
+    /// First sample frame of the loop.
+    pub start: u32,
+    /// Last sample frame of the loop, inclusive.
+    pub end: u32,
+}
+
+/// The `smpl` chunk.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SamplerChunk {
+    /// The MIDI note at which the sample plays back at its original pitch.
+    pub midi_unity_note: u32,
+    /// How far above `midi_unity_note` the sample is pitched, in units of 1/2^32 semitones.
+    pub midi_pitch_fraction: u32,
+    pub loops: Vec<SampleLoop>,
+}
+
+/// The `inst` chunk.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct InstrumentChunk {
+    /// The MIDI note at which the sample plays back at its original pitch.
+    pub unshifted_note: u8,
+    /// Pitch shift, in cents, needed to play the sample back at `unshifted_note`.
+    pub fine_tune: i8,
+    /// Gain, in dB.
+    pub gain: i8,
+    pub low_note: u8,
+    pub high_note: u8,
+    pub low_velocity: u8,
+    pub high_velocity: u8,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WavMetadata {
+    pub sampler: Option<SamplerChunk>,
+    pub instrument: Option<InstrumentChunk>,
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+impl SamplerChunk {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let num_loops = u32_at(data, 28)? as usize;
+        let loops = (0..num_loops)
+            .map_while(|i| {
+                let offset = 36 + i * 24;
+                Some(SampleLoop {
+                    start: u32_at(data, offset + 8)?,
+                    end: u32_at(data, offset + 12)?,
+                })
+            })
+            .collect();
+        Some(Self {
+            midi_unity_note: u32_at(data, 12)?,
+            midi_pitch_fraction: u32_at(data, 16)?,
+            loops,
+        })
+    }
+
+    /// The pitch of the sample as a fractional MIDI note.
+    pub fn root_note(&self) -> f32 {
+        self.midi_unity_note as f32 + self.midi_pitch_fraction as f32 / 2f32.powi(32)
+    }
+}
+
+impl InstrumentChunk {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let data = data.get(0..7)?;
+        Some(Self {
+            unshifted_note: data[0],
+            fine_tune: data[1] as i8,
+            gain: data[2] as i8,
+            low_note: data[3],
+            high_note: data[4],
+            low_velocity: data[5],
+            high_velocity: data[6],
+        })
+    }
+
+    /// The pitch of the sample as a fractional MIDI note.
+    pub fn root_note(&self) -> f32 {
+        self.unshifted_note as f32 - self.fine_tune as f32 / 100.0
+    }
+}
+
+impl WavMetadata {
+    /// Read the metadata chunks of a RIFF WAVE file. Chunks that are missing or malformed are
+    /// left as `None`.
+    pub fn read(bytes: &[u8]) -> Self {
+        let mut meta = Self::default();
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return meta;
+        }
+
+        let mut offset = 12;
+        while let Some(size) = u32_at(bytes, offset + 4) {
+            let start = offset + 8;
+            let end = start.saturating_add(size as usize).min(bytes.len());
+            let data = &bytes[start..end];
+            match &bytes[offset..offset + 4] {
+                b"smpl" => meta.sampler = SamplerChunk::parse(data),
+                b"inst" => meta.instrument = InstrumentChunk::parse(data),
+                _ => (),
+            }
+            offset = end + (end & 1);
+        }
+        meta
+    }
+
+    /// The pitch of the sample as a fractional MIDI note, from the `inst` chunk or else the
+    /// `smpl` chunk.
+    pub fn root_note(&self) -> Option<f32> {
+        match (&self.instrument, &self.sampler) {
+            (Some(inst), _) => Some(inst.root_note()),
+            (None, Some(smpl)) => Some(smpl.root_note()),
+            (None, None) => None,
+        }
+    }
+
+    /// The first loop of the `smpl` chunk, if there is one.
+    pub fn sample_loop(&self) -> Option<SampleLoop> {
+        self.sampler.as_ref()?.loops.first().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav(chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut body = b"WAVE".to_vec();
+        for (id, data) in chunks {
+            body.extend_from_slice(*id);
+            body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            body.extend_from_slice(data);
+            if data.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        bytes.extend(body);
+        bytes
+    }
+
+    /// A `smpl` chunk declaring `num_loops` loops and holding `loops`.
+    fn smpl(note: u32, fraction: u32, num_loops: u32, loops: &[(u32, u32)]) -> Vec<u8> {
+        let mut data = vec![0; 36];
+        data[12..16].copy_from_slice(&note.to_le_bytes());
+        data[16..20].copy_from_slice(&fraction.to_le_bytes());
+        data[28..32].copy_from_slice(&num_loops.to_le_bytes());
+        for &(start, end) in loops {
+            let mut l = vec![0; 24];
+            l[8..12].copy_from_slice(&start.to_le_bytes());
+            l[12..16].copy_from_slice(&end.to_le_bytes());
+            data.extend(l);
+        }
+        data
+    }
+
+    #[test]
+    fn reads_smpl_and_inst() {
+        let bytes = wav(&[
+            (b"fmt ", vec![0; 16]),
+            (b"smpl", smpl(60, 1 << 31, 1, &[(100, 199)])),
+            (b"data", vec![0; 3]),
+            (b"inst", vec![62, -50i8 as u8, -3i8 as u8, 0, 127, 1, 127]),
+        ]);
+        let meta = WavMetadata::read(&bytes);
+        let sampler = meta.sampler.as_ref().unwrap();
+        assert_eq!(sampler.midi_unity_note, 60);
+        assert_eq!(sampler.root_note(), 60.5);
+        assert_eq!(
+            meta.sample_loop(),
+            Some(SampleLoop {
+                start: 100,
+                end: 199
+            })
+        );
+        let inst = meta.instrument.as_ref().unwrap();
+        assert_eq!((inst.fine_tune, inst.gain, inst.high_note), (-50, -3, 127));
+        // `inst` takes precedence
+        assert_eq!(meta.root_note(), Some(62.5));
+
+        let meta = WavMetadata::read(&wav(&[(b"smpl", smpl(48, 0, 0, &[]))]));
+        assert_eq!(meta.root_note(), Some(48.0));
+        assert_eq!(meta.sample_loop(), None);
+    }
+
+    #[test]
+    fn truncated_chunks_are_ignored() {
+        let mut short = smpl(60, 0, 0, &[]);
+        short.truncate(30);
+        let meta = WavMetadata::read(&wav(&[(b"smpl", short), (b"inst", vec![60; 6])]));
+        assert_eq!(meta, WavMetadata::default());
+
+        // A chunk cut off by the end of the file is read as far as it goes
+        let mut bytes = wav(&[(b"smpl", smpl(60, 0, 1, &[(10, 20)]))]);
+        bytes.truncate(bytes.len() - 12);
+        let meta = WavMetadata::read(&bytes);
+        assert_eq!(meta.root_note(), Some(60.0));
+        assert_eq!(meta.sample_loop(), None);
+    }
+
+    #[test]
+    fn loop_count_is_limited_to_the_data() {
+        let loops = [(10, 20), (30, 40)];
+        let meta = WavMetadata::read(&wav(&[(b"smpl", smpl(60, 0, 5, &loops))]));
+        assert_eq!(meta.sampler.unwrap().loops.len(), 2);
+
+        let meta = WavMetadata::read(&wav(&[(b"smpl", smpl(60, 0, u32::MAX, &loops))]));
+        assert_eq!(meta.sampler.unwrap().loops.len(), 2);
+
+        let meta = WavMetadata::read(&wav(&[(b"smpl", smpl(60, 0, 1, &loops))]));
+        assert_eq!(meta.sampler.unwrap().loops.len(), 1);
+    }
+
+    #[test]
+    fn other_files_have_no_metadata() {
+        let mut bytes = wav(&[(b"smpl", smpl(60, 0, 0, &[]))]);
+        bytes[0..4].copy_from_slice(b"FORM");
+        assert_eq!(WavMetadata::read(&bytes), WavMetadata::default());
+        assert_eq!(WavMetadata::read(b"RIFF"), WavMetadata::default());
+    }
+}